@@ -0,0 +1,38 @@
+#[macro_use]
+extern crate criterion;
+extern crate image;
+extern crate pic2term;
+
+use criterion::{BenchmarkId, Criterion};
+use image::{Rgb, RgbImage};
+use pic2term::{dither, ColorSpace, Matcher, ANSI_COLORS, FLOYD_STEINBERG_KERNEL};
+
+// Red across and green down, so the pixels land all over the palette rather than on a few colors
+fn gradient(size: u32) -> RgbImage {
+    RgbImage::from_fn(size, size, |x, y| Rgb { data: [(x * 255 / size) as u8, (y * 255 / size) as u8, 128] })
+}
+
+fn bench_dither(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dither");
+    for &size in &[32, 128, 512] {
+        let img = gradient(size);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &img, |b, img| {
+            b.iter(|| dither(img.clone(), &ANSI_COLORS, &FLOYD_STEINBERG_KERNEL, 1.0, false, 2.2, ColorSpace::Rgb))
+        });
+    }
+    group.finish();
+}
+
+// The search every kind of dithering matches pixels with, in both of the spaces it can measure in
+fn bench_matcher(c: &mut Criterion) {
+    let pixels = gradient(16).into_raw();
+    let mut group = c.benchmark_group("matcher");
+    for &(name, space) in &[("rgb", ColorSpace::Rgb), ("lab", ColorSpace::Lab)] {
+        let matcher = Matcher::new(&ANSI_COLORS, 2.2, space);
+        group.bench_function(name, |b| b.iter(|| pixels.chunks(3).map(|pixel| matcher.nearest(pixel)).sum::<usize>()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_dither, bench_matcher);
+criterion_main!(benches);
@@ -0,0 +1,2186 @@
+extern crate ansi_term;
+extern crate base64;
+extern crate image;
+#[macro_use]
+extern crate itertools;
+#[macro_use]
+extern crate log;
+extern crate rayon;
+extern crate termsize;
+
+use ansi_term::{ANSIString, ANSIStrings, Colour, Style};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use image::{imageops, png, ColorType, FilterType, GenericImage, Rgb, RgbImage, RgbaImage};
+use itertools::Itertools;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use termsize::Size;
+
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::io::{self, Write};
+use std::iter;
+use std::str;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Instant;
+
+#[derive(Clone, Copy)]
+pub enum Dimension {
+    Cells(u16),
+    Percent(u16),
+}
+impl Dimension {
+    pub fn resolve(self, terminal: Option<u16>) -> Option<u16> {
+        match self {
+            Dimension::Cells(n) => Some(n),
+            Dimension::Percent(p) => terminal.map(|n| (n as u32 * p as u32 / 100).clamp(1, u16::MAX as u32) as u16),
+        }
+    }
+}
+
+/// Works out how many columns and pixel rows to render at for an image of the given aspect ratio,
+/// going by the terminal's size wherever nothing's asked for, or `None` if that's needed but unknown.
+pub fn determine_size(aspect: f32, desired_w: Option<Dimension>, desired_h: Option<Dimension>, reserve_rows: u16, cell_aspect: f32, terminal: Option<&Size>) -> Option<(u16, u16)> {
+    // Every cell holds two pixels one above the other, which only come out square when cells are
+    // twice as tall as they're wide. Otherwise the image needs squashing or stretching to make up
+    // for it.
+    let aspect = aspect * cell_aspect / 2.0;
+
+    // A percentage needs the terminal's size to mean anything
+    let desired_w = match desired_w {
+        Some(desired_w) => Some(desired_w.resolve(terminal.map(|size| size.cols))?),
+        None => None,
+    };
+    let desired_h = match desired_h {
+        Some(desired_h) => Some(desired_h.resolve(terminal.map(|size| size.rows))?),
+        None => None,
+    };
+
+    // To note, we're outputting with double density vertically due to the
+    // Unicode bottom-half character, so we need to consider that in size
+    // calculations if the user provided a height. Anything too tall to double is about as
+    // tall as it gets anyway.
+    let desired_h = desired_h.map(|n| n.saturating_mul(2));
+
+    let size = if let Some(desired_w) = desired_w {
+        if let Some(desired_h) = desired_h {
+            Some((desired_w, desired_h))
+        } else {
+            // Width is known, height is not. Match height to the aspect ratio
+            Some((desired_w, (desired_w as f32 / aspect) as u16))
+        }
+    } else {
+        if let Some(desired_h) = desired_h {
+            // Height is known, width is not. Match width to the aspect ratio
+            Some(((desired_h as f32 * aspect) as u16, desired_h))
+        } else {
+            // Width and height are unknown
+            match terminal {
+                Some(&Size { rows: h, cols: w }) => {
+                    // Leave the reserved rows free, but always draw something
+                    let h = h.saturating_sub(reserve_rows).max(1);
+                    // Our terminal is virtually twice as tall as we otherwise believe it to be.
+                    let h = h.saturating_mul(2);
+
+                    // Take the smaller dimension and scale the other to fit. The other side is
+                    // worked out from the aspect ratio directly, since going through a rescaled
+                    // size that's too big could saturate it first and throw the scale off.
+                    if w < h {
+                        let rescaled_h = w as f32 / aspect;
+                        if rescaled_h > h as f32 {
+                            Some(((h as f32 * aspect) as u16, h))
+                        } else {
+                            Some((w, rescaled_h as u16))
+                        }
+                    } else { // h <= w
+                        let rescaled_w = h as f32 * aspect;
+                        if rescaled_w > w as f32 {
+                            Some((w, (w as f32 / aspect) as u16))
+                        } else {
+                            Some((rescaled_w as u16, h))
+                        }
+                    }
+                },
+                None => None
+            }
+        }
+    };
+    // However thin the image, there's always at least a cell of it to draw
+    size.map(|(w, h)| (w.max(1), h.max(1)))
+}
+
+pub fn clamp_size((w, h): (u16, u16), (max_w, max_h): (u16, u16)) -> (u16, u16) {
+    if w <= max_w && h <= max_h {
+        return (w.max(1), h.max(1));
+    }
+    // Shrink both sides by whichever needs it more, keeping the shape that was asked for
+    let scale = (max_w as f32 / w as f32).min(max_h as f32 / h as f32);
+    (((w as f32 * scale) as u16).clamp(1, max_w.max(1)), ((h as f32 * scale) as u16).clamp(1, max_h.max(1)))
+}
+
+pub fn gradient(width: u32, height: u32, top: [u8; 3], bottom: [u8; 3]) -> RgbImage {
+    // Blends from the top color on the first row to the bottom color on the last
+    let span = height.saturating_sub(1).max(1) as f32;
+    RgbImage::from_fn(width, height, |_, y| {
+        let t = y as f32 / span;
+        let mut data = [0; 3];
+        for (channel, (a, b)) in data.iter_mut().zip(top.iter().zip(&bottom)) {
+            *channel = (*a as f32 + (*b as f32 - *a as f32) * t).round() as u8;
+        }
+        Rgb { data }
+    })
+}
+pub fn composite(img: &RgbaImage, backdrop: &RgbImage) -> RgbImage {
+    RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let fg = img.get_pixel(x, y).data;
+        let bg = backdrop.get_pixel(x, y).data;
+        let alpha = fg[3] as u16;
+
+        let mut data = [0; 3];
+        for (channel, (a, b)) in data.iter_mut().zip(fg.iter().zip(&bg)) {
+            *channel = ((*a as u16 * alpha + *b as u16 * (255 - alpha) + 127) / 255) as u8;
+        }
+        Rgb { data }
+    })
+}
+
+/// Pads an image out to the given aspect ratio with a fill color on both sides, keeping it in the
+/// middle, so it fits a box of that shape without being stretched.
+pub fn letterbox(img: RgbImage, aspect: f32, fill: [u8; 3]) -> RgbImage {
+    let (w, h) = img.dimensions();
+    let (box_w, box_h) = if (w as f32 / h as f32) < aspect {
+        (((h as f32 * aspect).round() as u32).max(w), h)
+    } else {
+        (w, ((w as f32 / aspect).round() as u32).max(h))
+    };
+    if (box_w, box_h) == (w, h) {
+        return img;
+    }
+    let mut boxed = RgbImage::from_pixel(box_w, box_h, Rgb { data: fill });
+    boxed.copy_from(&img, (box_w - w) / 2, (box_h - h) / 2);
+    boxed
+}
+
+/// Crops an image down to the given aspect ratio, cutting the same amount off both sides, so it
+/// covers a box of that shape without being stretched.
+pub fn crop_to_aspect(mut img: RgbImage, aspect: f32) -> RgbImage {
+    let (w, h) = img.dimensions();
+    let (box_w, box_h) = if (w as f32 / h as f32) > aspect {
+        (((h as f32 * aspect).round() as u32).clamp(1, w), h)
+    } else {
+        (w, ((w as f32 / aspect).round() as u32).clamp(1, h))
+    };
+    if (box_w, box_h) == (w, h) {
+        return img;
+    }
+    imageops::crop(&mut img, (w - box_w) / 2, (h - box_h) / 2, box_w, box_h).to_image()
+}
+
+pub fn autocrop(mut img: RgbImage, weights: Luma) -> RgbImage {
+    let (width, height) = img.dimensions();
+    let brightness = img.pixels().map(|p| luma(p.data, weights)).collect::<Vec<_>>();
+    let at = |x: u32, y: u32| brightness[(x + y * width) as usize];
+
+    // The energy of a pixel is how sharply the luminance changes around it
+    let energy = (0..height).flat_map(|y| (0..width).map(move |x| (x, y))).map(|(x, y)| {
+        let dx = at((x + 1).min(width - 1), y) - at(x.saturating_sub(1), y);
+        let dy = at(x, (y + 1).min(height - 1)) - at(x, y.saturating_sub(1));
+        dx.abs() + dy.abs()
+    }).collect::<Vec<_>>();
+    let max = energy.iter().cloned().fold(0.0, f32::max);
+    // Ignore the faint texture of noise, compression, or paper grain
+    let threshold = (max * 0.1).max(16.0);
+
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (i, _) in energy.iter().enumerate().filter(|&(_, &e)| e >= threshold) {
+        let (x, y) = (i as u32 % width, i as u32 / width);
+        bounds = Some(match bounds {
+            Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+            None => (x, y, x, y),
+        });
+    }
+
+    match bounds {
+        Some((x0, y0, x1, y1)) => imageops::crop(&mut img, x0, y0, x1 - x0 + 1, y1 - y0 + 1).to_image(),
+        // Nothing stands out, so there's nothing to crop to
+        None => img,
+    }
+}
+
+pub fn dejpeg(img: &RgbImage) -> RgbImage {
+    // Split into luma and chroma with the BT.601 weights JPEG uses
+    let (width, height) = img.dimensions();
+    let ycc = img.pixels().map(|p| {
+        let [r, g, b] = p.data;
+        let (r, g, b) = (r as f32, g as f32, b as f32);
+        [0.299 * r + 0.587 * g + 0.114 * b,
+         -0.168736 * r - 0.331264 * g + 0.5 * b,
+         0.5 * r - 0.418688 * g - 0.081312 * b]
+    }).collect::<Vec<_>>();
+
+    RgbImage::from_fn(width, height, |x, y| {
+        // Smooth the chroma over a 3x3 neighborhood, which is where subsampling bleeds, but
+        // leave the luma alone so edges stay sharp
+        let (mut cb, mut cr, mut n) = (0.0, 0.0, 0.0);
+        for ny in y.saturating_sub(1)..(y + 2).min(height) {
+            for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                let neighbor = ycc[(nx + ny * width) as usize];
+                cb += neighbor[1];
+                cr += neighbor[2];
+                n += 1.0;
+            }
+        }
+        let (luma, cb, cr) = (ycc[(x + y * width) as usize][0], cb / n, cr / n);
+
+        let rgb = [luma + 1.402 * cr, luma - 0.344136 * cb - 0.714136 * cr, luma + 1.772 * cb];
+        let mut data = [0; 3];
+        for (channel, value) in data.iter_mut().zip(&rgb) {
+            *channel = value.round().clamp(0.0, 255.0) as u8;
+        }
+        Rgb { data }
+    })
+}
+
+// Error diffusion kernels, as (dx, dy, numerator, denominator) offsets from the pixel being
+// quantized along with the fraction of its error each one receives.
+pub static FLOYD_STEINBERG_KERNEL: [(i32, i32, i32, i32); 4] = [
+    (1, 0, 7, 16),
+    (-1, 1, 3, 16), (0, 1, 5, 16), (1, 1, 1, 16),
+];
+// Only diffuses three quarters of the error, which keeps more contrast
+pub static ATKINSON_KERNEL: [(i32, i32, i32, i32); 6] = [
+    (1, 0, 1, 8), (2, 0, 1, 8),
+    (-1, 1, 1, 8), (0, 1, 1, 8), (1, 1, 1, 8),
+    (0, 2, 1, 8),
+];
+pub static JARVIS_KERNEL: [(i32, i32, i32, i32); 12] = [
+    (1, 0, 7, 48), (2, 0, 5, 48),
+    (-2, 1, 3, 48), (-1, 1, 5, 48), (0, 1, 7, 48), (1, 1, 5, 48), (2, 1, 3, 48),
+    (-2, 2, 1, 48), (-1, 2, 3, 48), (0, 2, 5, 48), (1, 2, 3, 48), (2, 2, 1, 48),
+];
+// Jarvis' shape with weights that are quicker to work out and come out a little sharper
+pub static STUCKI_KERNEL: [(i32, i32, i32, i32); 12] = [
+    (1, 0, 8, 42), (2, 0, 4, 42),
+    (-2, 1, 2, 42), (-1, 1, 4, 42), (0, 1, 8, 42), (1, 1, 4, 42), (2, 1, 2, 42),
+    (-2, 2, 1, 42), (-1, 2, 2, 42), (0, 2, 4, 42), (1, 2, 2, 42), (2, 2, 1, 42),
+];
+pub static SIERRA_KERNEL: [(i32, i32, i32, i32); 10] = [
+    (1, 0, 5, 32), (2, 0, 3, 32),
+    (-2, 1, 2, 32), (-1, 1, 4, 32), (0, 1, 5, 32), (1, 1, 4, 32), (2, 1, 2, 32),
+    (-1, 2, 2, 32), (0, 2, 3, 32), (1, 2, 2, 32),
+];
+// Sierra cut down to its three nearest neighbours, nearly as good as Floyd-Steinberg for less work
+pub static SIERRA_LITE_KERNEL: [(i32, i32, i32, i32); 3] = [
+    (1, 0, 2, 4),
+    (-1, 1, 1, 4), (0, 1, 1, 4),
+];
+
+#[derive(Clone, Copy)]
+pub enum Dither {
+    // The nearest palette color for every pixel, which keeps pixel art's edges sharp
+    None,
+    FloydSteinberg,
+    Atkinson,
+    Jarvis,
+    Stucki,
+    Sierra,
+    SierraLite,
+    // Ordered dithering with 4x4 and 8x8 Bayer matrices, which doesn't diffuse any error
+    Bayer4,
+    Bayer8,
+}
+impl Dither {
+    pub fn kernel(self) -> &'static [(i32, i32, i32, i32)] {
+        match self {
+            Dither::None | Dither::Bayer4 | Dither::Bayer8 => &[],
+            Dither::FloydSteinberg => &FLOYD_STEINBERG_KERNEL,
+            Dither::Atkinson => &ATKINSON_KERNEL,
+            Dither::Jarvis => &JARVIS_KERNEL,
+            Dither::Stucki => &STUCKI_KERNEL,
+            Dither::Sierra => &SIERRA_KERNEL,
+            Dither::SierraLite => &SIERRA_LITE_KERNEL,
+        }
+    }
+    // The size of the Bayer matrix to dither with, if any
+    pub fn bayer(self) -> Option<u32> {
+        match self {
+            Dither::Bayer4 => Some(4),
+            Dither::Bayer8 => Some(8),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Luma {
+    // ITU-R BT.601, from standard definition video and what JPEG uses
+    Rec601,
+    // ITU-R BT.709, from HD video and shared with sRGB
+    Rec709,
+    // Every channel counting the same
+    Average,
+}
+
+/// How bright a color is, from 0 to 255, with its channels weighted the way `weights` says.
+pub fn luma(data: [u8; 3], weights: Luma) -> f32 {
+    let [r, g, b] = match weights {
+        Luma::Rec601 => [0.299, 0.587, 0.114],
+        Luma::Rec709 => [0.2126, 0.7152, 0.0722],
+        Luma::Average => [1.0 / 3.0; 3],
+    };
+    r * data[0] as f32 + g * data[1] as f32 + b * data[2] as f32
+}
+pub fn grayscale(img: &mut RgbImage, weights: Luma) {
+    for pixel in img.pixels_mut() {
+        let value = luma(pixel.data, weights).round() as u8;
+        pixel.data = [value; 3];
+    }
+}
+// Scales every channel's distance from the midpoint by `contrast`, then shifts it by `brightness`
+pub fn adjust(img: &mut RgbImage, brightness: i16, contrast: f32) {
+    for pixel in img.pixels_mut() {
+        for channel in pixel.data.iter_mut() {
+            let value = (*channel as f32 - 128.0) * contrast + 128.0 + brightness as f32;
+            *channel = value.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+pub fn invert(img: &mut RgbImage) {
+    for pixel in img.pixels_mut() {
+        for channel in pixel.data.iter_mut() {
+            *channel = 255 - *channel;
+        }
+    }
+}
+pub fn equalize(img: &mut RgbImage, weights: Luma) {
+    let mut histogram = [0u32; 256];
+    for pixel in img.pixels() {
+        histogram[luma(pixel.data, weights).round() as usize] += 1;
+    }
+    let mut cdf = [0u32; 256];
+    let mut total = 0;
+    for (cumulative, count) in cdf.iter_mut().zip(histogram.iter()) {
+        total += *count;
+        *cumulative = total;
+    }
+    let cdf_min = cdf.iter().cloned().find(|&n| n > 0).unwrap_or(0);
+    if total == cdf_min {
+        // A single tone, there's nothing to spread out
+        return;
+    }
+
+    for pixel in img.pixels_mut() {
+        let old = luma(pixel.data, weights);
+        let new = (cdf[old.round() as usize] - cdf_min) as f32 / (total - cdf_min) as f32 * 255.0;
+        // Scale the channels together so the hue is kept
+        for channel in pixel.data.iter_mut() {
+            let value = if old > 0.0 { *channel as f32 * new / old } else { new };
+            *channel = value.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+pub fn cube_palette(bits: u8) -> Vec<[u8; 3]> {
+    // Evenly spaced steps from 0 to 255 along each channel
+    let steps = 1u32 << bits;
+    let levels = (0..steps).map(|i| (i * 255 / (steps - 1)) as u8).collect::<Vec<_>>();
+    iproduct!(levels.iter(), levels.iter(), levels.iter())
+        .map(|(&r, &g, &b)| [r, g, b])
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct Lut {
+    size: usize,
+    domain_min: [f32; 3],
+    domain_max: [f32; 3],
+    // Indexed by red, then green, then blue, with red changing fastest
+    table: Vec<[f32; 3]>,
+}
+
+pub fn parse_lut(text: &str) -> Result<Lut, String> {
+    let mut size = None;
+    let mut domain_min = [0.0; 3];
+    let mut domain_max = [1.0; 3];
+    let mut table = Vec::new();
+
+    fn triple<'a, I: Iterator<Item = &'a str>>(words: I, line: &str) -> Result<[f32; 3], String> {
+        let values = words.map(str::parse::<f32>).collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("'{}' is not a valid .cube line: {}", line, e))?;
+        match values[..] {
+            [a, b, c] => Ok([a, b, c]),
+            _ => Err(format!("'{}' does not have three values", line)),
+        }
+    }
+
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("TITLE") => (),
+            Some("LUT_3D_SIZE") => {
+                let n = words.next().and_then(|n| n.parse::<usize>().ok())
+                    .ok_or_else(|| format!("'{}' does not have a valid size", line))?;
+                if !(2..=256).contains(&n) {
+                    return Err(format!("a LUT size of {} is not between 2 and 256", n));
+                }
+                size = Some(n);
+            },
+            Some("LUT_1D_SIZE") => return Err(String::from("1D LUTs are not supported")),
+            Some("DOMAIN_MIN") => domain_min = triple(words, line)?,
+            Some("DOMAIN_MAX") => domain_max = triple(words, line)?,
+            Some(_) => table.push(triple(line.split_whitespace(), line)?),
+            None => (),
+        }
+    }
+
+    let size = size.ok_or_else(|| String::from("the LUT does not declare a LUT_3D_SIZE"))?;
+    if table.len() != size * size * size {
+        return Err(format!("a LUT of size {} needs {} entries, but has {}", size, size * size * size, table.len()));
+    }
+    Ok(Lut { size, domain_min, domain_max, table })
+}
+
+pub fn apply_lut(img: &mut RgbImage, lut: &Lut) {
+    let n = lut.size;
+    let at = |r: usize, g: usize, b: usize| lut.table[r + g * n + b * n * n];
+
+    for pixel in img.pixels_mut() {
+        // Find where the color falls in the grid, and the two grid points on each side of it
+        let mut lower = [0; 3];
+        let mut upper = [0; 3];
+        let mut t = [0.0; 3];
+        for (i, channel) in pixel.data.iter().enumerate() {
+            let range = lut.domain_max[i] - lut.domain_min[i];
+            let position = ((*channel as f32 / 255.0 - lut.domain_min[i]) / range * (n - 1) as f32)
+                .clamp(0.0, (n - 1) as f32);
+            lower[i] = position.floor() as usize;
+            upper[i] = (lower[i] + 1).min(n - 1);
+            t[i] = position - lower[i] as f32;
+        }
+
+        // Blend the eight surrounding grid points, one axis at a time
+        let lerp = |a: [f32; 3], b: [f32; 3], t: f32| [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t];
+        let along_r = |g: usize, b: usize| lerp(at(lower[0], g, b), at(upper[0], g, b), t[0]);
+        let along_g = |b: usize| lerp(along_r(lower[1], b), along_r(upper[1], b), t[1]);
+        let color = lerp(along_g(lower[2]), along_g(upper[2]), t[2]);
+
+        for (channel, value) in pixel.data.iter_mut().zip(&color) {
+            *channel = (value * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+pub fn halve(mut img: RgbImage, steps: u16, w: u32, h: u32) -> RgbImage {
+    for _ in 0..steps {
+        // Halving past the target size would only throw away detail the final resize could use
+        let (half_w, half_h) = (img.width() / 2, img.height() / 2);
+        if half_w < w || half_h < h {
+            break;
+        }
+        img = imageops::resize(&img, half_w, half_h, FilterType::Triangle);
+    }
+    img
+}
+
+#[derive(Clone, Copy)]
+pub enum ColorSpace {
+    Rgb,
+    // CIELAB, where distances follow perceived differences much more closely
+    Lab,
+}
+
+// Converts linear RGB, scaled up to 65535, to CIELAB under a D65 white point
+fn lab(pixel: &[i32]) -> [f32; 3] {
+    let [r, g, b] = [pixel[0] as f32 / 65535.0, pixel[1] as f32 / 65535.0, pixel[2] as f32 / 65535.0];
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+    let f = |t: f32| if t > 216.0 / 24389.0 { t.cbrt() } else { t * 841.0 / 108.0 + 4.0 / 29.0 };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+// How many bins each channel is split into when caching the nearest color
+const CACHE_BINS: usize = 32;
+// How many times a bin is searched like any other pixel before it's worth the eight searches it
+// takes to work out, so small images don't pay for bins they hardly use
+const BIN_VISITS: u32 = 8;
+// A bin that's been worked out and turned out to be split between colors
+const MIXED_BIN: u32 = u32::MAX;
+
+/// Finds the nearest palette colors to pixels, the same way for every kind of dithering: in linear
+/// light under a gamma, measured in a color space. Equally distant colors go to the darker one, then
+/// to the lower index.
+pub struct Matcher {
+    // How bright each color is, for breaking ties
+    luminance: Vec<u32>,
+    space: ColorSpace,
+    // Each sRGB level in linear light, scaled up to 65535
+    linear: Vec<i32>,
+    // The palette in linear light
+    palette: Vec<[i32; 3]>,
+    // Where each color sits in the space distances are measured in, kept as integers so they
+    // compare exactly
+    positions: Vec<[i64; 3]>,
+    // The palette's indices laid out as a k-d tree: each range is split around its middle entry,
+    // with nothing before it further along the range's axis and nothing after it less far
+    tree: Vec<usize>,
+    // For RGB, how many times each bin of similar colors has come up, and then once it's been worked
+    // out, the color everything in it comes out as (past `BIN_VISITS`) or `MIXED_BIN`. Distances in
+    // CIELAB don't follow the bins, so it's left empty for that.
+    bins: Vec<AtomicU32>,
+}
+
+impl Matcher {
+    /// Panics if there are no colors to match against.
+    pub fn new(colors: &[[u8; 3]], gamma: f32, space: ColorSpace) -> Matcher {
+        assert!(!colors.is_empty(), "Matching needs at least one color");
+        let linear = linear_table(gamma);
+        let palette = colors.iter().map(|col| [linear[col[0] as usize], linear[col[1] as usize], linear[col[2] as usize]])
+            .collect::<Vec<_>>();
+        let positions = palette.iter().map(|col| Matcher::position(space, col)).collect::<Vec<_>>();
+        let mut tree = (0..colors.len()).collect::<Vec<_>>();
+        Matcher::build(&mut tree, &positions, 0);
+        let bins = match space {
+            ColorSpace::Rgb => (0..CACHE_BINS * CACHE_BINS * CACHE_BINS).map(|_| AtomicU32::new(0)).collect(),
+            ColorSpace::Lab => Vec::new(),
+        };
+        let luminance = colors.iter().map(|&col| luminance(col)).collect();
+        Matcher { luminance, space, linear, palette, positions, tree, bins }
+    }
+
+    fn build(tree: &mut [usize], positions: &[[i64; 3]], depth: usize) {
+        if tree.len() <= 1 {
+            return;
+        }
+        let axis = depth % 3;
+        let mid = tree.len() / 2;
+        tree.select_nth_unstable_by_key(mid, |&idx| positions[idx][axis]);
+        let (before, after) = tree.split_at_mut(mid);
+        Matcher::build(before, positions, depth + 1);
+        Matcher::build(&mut after[1..], positions, depth + 1);
+    }
+
+    fn position(space: ColorSpace, pixel: &[i32]) -> [i64; 3] {
+        match space {
+            ColorSpace::Rgb => [pixel[0] as i64, pixel[1] as i64, pixel[2] as i64],
+            ColorSpace::Lab => lab(pixel).map(|n| (n * 1000.0).round() as i64),
+        }
+    }
+
+    fn search(&self, tree: &[usize], depth: usize, position: &[i64; 3], best: &mut (i64, usize)) {
+        if tree.is_empty() {
+            return;
+        }
+        let mid = tree.len() / 2;
+        let idx = tree[mid];
+        let distance = position.iter()
+            .zip(&self.positions[idx])
+            .map(|(a, b)| a - b)
+            .map(|n| n * n)
+            .sum::<i64>();
+        if distance < best.0 || distance == best.0 && self.darker(idx, best.1) {
+            *best = (distance, idx);
+        }
+        let axis = depth % 3;
+        let offset = position[axis] - self.positions[idx][axis];
+        let (near, far) = if offset < 0 { (&tree[..mid], &tree[mid + 1..]) } else { (&tree[mid + 1..], &tree[..mid]) };
+        self.search(near, depth + 1, position, best);
+        // Colors across the split are at least this far away, and one exactly as far still has to
+        // be looked at in case it wins the tie
+        if offset * offset <= best.0 {
+            self.search(far, depth + 1, position, best);
+        }
+    }
+
+    // Ties between equally distant colors go to the darker one, then to the lower index, so the
+    // palette's ordering doesn't decide what flat regions become
+    fn darker(&self, idx: usize, than: usize) -> bool {
+        (self.luminance[idx], idx) < (self.luminance[than], than)
+    }
+
+    // Which color a bin's pixels all come out as, if it's the same for every one of them. What's
+    // nearest to a color (ties included, since they're broken the same way everywhere) is a convex
+    // region, so when all eight corners of the bin agree everything between them does too.
+    fn bin_color(&self, [r, g, b]: [usize; 3]) -> Option<usize> {
+        let bin_size = 65536 / CACHE_BINS;
+        let corner = |n: usize, far: usize| (n * bin_size + far * (bin_size - 1)) as i32;
+        let mut corners = (0..8).map(|i| self.search_all(&[corner(r, i & 1), corner(g, (i >> 1) & 1), corner(b, i >> 2)]));
+        let first = corners.next().expect("A bin has corners");
+        if corners.all(|idx| idx == first) { Some(first) } else { None }
+    }
+
+    /// The index of the color nearest to an sRGB pixel.
+    pub fn nearest(&self, pixel: &[u8]) -> usize {
+        self.nearest_linear(&[self.linear[pixel[0] as usize], self.linear[pixel[1] as usize], self.linear[pixel[2] as usize]])
+    }
+
+    // Error diffusion works in linear light already, and drifts off the levels an sRGB pixel can have
+    fn nearest_linear(&self, pixel: &[i32]) -> usize {
+        if let ColorSpace::Rgb = self.space {
+            let bin = [0, 1, 2].map(|i| pixel[i] as usize * CACHE_BINS / 65536);
+            let state = &self.bins[(bin[0] * CACHE_BINS + bin[1]) * CACHE_BINS + bin[2]];
+            // Other threads only ever race to count a visit or to work out the same answer
+            match state.load(Ordering::Relaxed) {
+                MIXED_BIN => {},
+                visits if visits < BIN_VISITS => state.store(visits + 1, Ordering::Relaxed),
+                BIN_VISITS => match self.bin_color(bin) {
+                    Some(idx) => {
+                        state.store(BIN_VISITS + 1 + idx as u32, Ordering::Relaxed);
+                        return idx;
+                    },
+                    None => state.store(MIXED_BIN, Ordering::Relaxed),
+                },
+                settled => return (settled - BIN_VISITS - 1) as usize,
+            }
+        }
+        self.search_all(pixel)
+    }
+
+    fn search_all(&self, pixel: &[i32]) -> usize {
+        let position = Matcher::position(self.space, pixel);
+        let mut best = (i64::MAX, 0);
+        self.search(&self.tree, 0, &position, &mut best);
+        best.1
+    }
+}
+
+fn luminance(col: [u8; 3]) -> u32 {
+    299 * col[0] as u32 + 587 * col[1] as u32 + 114 * col[2] as u32
+}
+
+// Colors are matched (and their error spread) in linear light, scaled up to keep precision, where
+// distances follow how bright they actually look
+fn linear_table(gamma: f32) -> Vec<i32> {
+    (0..256).map(|n| ((n as f32 / 255.0).powf(gamma) * 65535.0).round() as i32).collect()
+}
+
+/// Finds the index of the palette color nearest to an sRGB pixel, matched the way rendering does
+/// by default, in linear RGB with a gamma of 2.2. Matching more than the odd pixel is quicker with a
+/// `Matcher` kept around for all of them.
+pub fn nearest_color(pixel: &[u8], palette: &[[u8; 3]]) -> usize {
+    thread_local! {
+        // The last palette asked about, with its matcher, so calling this pixel by pixel on the same
+        // palette only builds one
+        static LAST: RefCell<Option<(Vec<[u8; 3]>, Matcher)>> = const { RefCell::new(None) };
+    }
+    LAST.with(|last| {
+        let mut last = last.borrow_mut();
+        match *last {
+            Some((ref colors, ref matcher)) if colors[..] == *palette => matcher.nearest(pixel),
+            _ => {
+                let matcher = Matcher::new(palette, 2.2, ColorSpace::Rgb);
+                let idx = matcher.nearest(pixel);
+                *last = Some((palette.to_vec(), matcher));
+                idx
+            }
+        }
+    })
+}
+
+/// Dithers to the nearest colors, diffusing `strength` of each pixel's error through the kernel.
+/// With `serpentine` every other row is worked through right to left with the kernel mirrored,
+/// so the error doesn't all get pushed the same way and drag out into diagonal streaks.
+pub fn dither(img: RgbImage, colors: &[[u8; 3]], kernel: &[(i32, i32, i32, i32)], strength: f32, serpentine: bool, gamma: f32,
+              space: ColorSpace) -> Vec<usize> {
+    // The magic number is 3
+    let (width, height) = img.dimensions();
+    let mut res = vec![0; width as usize * height as usize];
+
+    let matcher = Matcher::new(colors, gamma, space);
+    let mut raw = img.into_raw().into_iter().map(|n| matcher.linear[n as usize]).collect::<Vec<_>>();
+
+    for y in 0..height {
+        let reversed = serpentine && y % 2 == 1;
+        for step in 0..width {
+            let x = if reversed { width - 1 - step } else { step };
+            let cur_idx = 3 * (x + y * width) as usize;
+
+            let (dithered_idx, diff) = {
+                let cur_pixel = &raw[cur_idx..cur_idx + 3];
+                let dithered_idx = matcher.nearest_linear(cur_pixel);
+                let diff = cur_pixel.iter()
+                    .zip(&matcher.palette[dithered_idx])
+                    .map(|(a, b)| ((*a - *b) as f32 * strength).round() as i32)
+                    .collect::<Vec<i32>>();
+
+                (dithered_idx, diff)
+            };
+
+            res[cur_idx / 3] = dithered_idx;
+
+            // Error meant for pixels past the edge the row started from is mirrored back onto the first
+            // columns, which have nothing behind them feeding them any. What's meant for past the edge
+            // it ends at is spread over the kernel's other pixels instead, so neither edge drifts
+            // lighter or darker than the rest. What would go past the bottom has nowhere left to go.
+            let target = |dx: i32, dy: i32| {
+                let tx = step as i32 + dx;
+                let tx = if tx < 0 { -tx - 1 } else { tx };
+                // Checked a side at a time, since a column past the right edge would otherwise index
+                // into the start of the next row
+                Some((tx, y as i32 + dy))
+                    .filter(|&(tx, ty)| tx < width as i32 && ty < height as i32)
+                    .map(|(tx, ty)| (if reversed { width as i32 - 1 - tx } else { tx }, ty))
+            };
+            let (kept, total) = kernel.iter()
+                .filter(|&&(_, dy, _, _)| y as i32 + dy < height as i32)
+                .fold((0, 0), |(kept, total), &(dx, dy, numerator, _)| {
+                    let kept = if target(dx, dy).is_some() { kept + numerator as i64 } else { kept };
+                    (kept, total + numerator as i64)
+                });
+
+            // This only supports dithering algorithms which modify ahead
+            for &(dx, dy, numerator, denominator) in kernel {
+                if let Some((tx, ty)) = target(dx, dy) {
+                    let idx = 3 * (tx as u32 + ty as u32 * width) as usize;
+                    for (channel, offset) in raw[idx..idx + 3].iter_mut().zip(&diff) {
+                        let share = *offset as i64 * numerator as i64 * total / (denominator as i64 * kept);
+                        *channel = (*channel + share as i32).clamp(0, 65535);
+                    }
+                }
+            }
+        }
+    }
+
+    res
+}
+
+// Maps every pixel straight to its nearest color, without diffusing any error or even working it
+// out, which is much quicker than dithering with an empty kernel
+pub fn nearest(img: RgbImage, colors: &[[u8; 3]], gamma: f32, space: ColorSpace) -> Vec<usize> {
+    let matcher = Matcher::new(colors, gamma, space);
+    img.pixels().map(|pixel| matcher.nearest(&pixel.data)).collect()
+}
+
+// Where a pixel falls in the order of an `n` by `n` Bayer matrix, with `n` a power of two
+fn bayer(n: u32, x: u32, y: u32) -> u32 {
+    // Every quadrant is the next smaller matrix spread out, offset in the order 0, 2, 3, 1
+    if n == 1 {
+        0
+    } else {
+        let half = n / 2;
+        4 * bayer(half, x % half, y % half) + [[0, 2], [3, 1]][(y / half) as usize][(x / half) as usize]
+    }
+}
+
+/// Dithers with an `n` by `n` Bayer matrix, nudging each pixel up or down by where it falls in the
+/// matrix before matching it to the nearest color. Nothing spreads between pixels, so any part of
+/// the image comes out the same however it's cropped, as long as it stays on the matrix's grid.
+pub fn ordered(img: RgbImage, colors: &[[u8; 3]], n: u32, gamma: f32, space: ColorSpace) -> Vec<usize> {
+    let matcher = Matcher::new(colors, gamma, space);
+    // About the distance between neighboring colors, if the palette were spread evenly
+    let spread = 255.0 / (colors.len() as f32).cbrt();
+    img.enumerate_pixels()
+        .map(|(x, y, pixel)| {
+            let offset = ((bayer(n, x % n, y % n) as f32 + 0.5) / (n * n) as f32 - 0.5) * spread;
+            matcher.nearest(&pixel.data.map(|c| (c as f32 + offset).round().clamp(0.0, 255.0) as u8))
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+pub enum Palette<'a> {
+    // A terminal palette, emitted as indices into it
+    Indexed(&'a [[u8; 3]]),
+    // A terminal palette as the user says it's actually shown, so every color can be trusted
+    Custom(Vec<[u8; 3]>),
+    // Arbitrary colors, emitted as truecolor
+    Rgb(Vec<[u8; 3]>),
+    // Every color, emitted as truecolor without any quantization
+    Truecolor,
+}
+
+impl<'a> Palette<'a> {
+    /// Whether the palette has any neutral grays in it to match grayscale images against.
+    pub fn has_grays(&self) -> bool {
+        match *self {
+            Palette::Indexed(colors) => colors.iter().any(is_neutral),
+            Palette::Custom(ref colors) | Palette::Rgb(ref colors) => colors.iter().any(is_neutral),
+            Palette::Truecolor => true,
+        }
+    }
+}
+
+fn is_neutral(color: &[u8; 3]) -> bool {
+    color[0] == color[1] && color[1] == color[2]
+}
+
+pub fn negate(colors: &[[u8; 3]], gamma: f32, space: ColorSpace) -> Vec<usize> {
+    // Match every color's inverse
+    let inverted = RgbImage::from_fn(colors.len() as u32, 1, |x, _| {
+        let [r, g, b] = colors[x as usize];
+        Rgb { data: [255 - r, 255 - g, 255 - b] }
+    });
+    nearest(inverted, colors, gamma, space)
+}
+
+fn quantize(mut img: RgbImage, colors: &[[u8; 3]], opts: &RenderOptions) -> Vec<usize> {
+    let (width, height) = img.dimensions();
+    // Diffused error reaches every pixel after it, so that always goes through the image in one piece
+    let diffusing = !opts.no_dither && opts.bayer.is_none();
+    let pool = if opts.threads > 1 && height > 1 && !diffusing {
+        ThreadPoolBuilder::new().num_threads(opts.threads).build().ok()
+    } else {
+        None
+    };
+    let quantize_band = |band: RgbImage| if opts.no_dither {
+        nearest(band, colors, opts.gamma, opts.colorspace)
+    } else if let Some(n) = opts.bayer {
+        ordered(band, colors, n, opts.gamma, opts.colorspace)
+    } else {
+        dither(band, colors, &opts.kernel, opts.strength, opts.serpentine, opts.gamma, opts.colorspace)
+    };
+    let indices = if let Some(pool) = pool {
+        // Every pixel is matched on its own, so horizontal bands can go to their own threads and come
+        // out just as they would all together. Bayer bands start on the matrix's grid to keep it lined up.
+        let band_h = height.div_ceil(opts.threads as u32).next_multiple_of(opts.bayer.unwrap_or(1));
+        let bands = (0..height).step_by(band_h as usize)
+            .map(|top| imageops::crop(&mut img, 0, top, width, band_h.min(height - top)).to_image())
+            .collect::<Vec<_>>();
+        pool.install(|| bands.into_par_iter().map(quantize_band).collect::<Vec<_>>()).concat()
+    } else {
+        quantize_band(img)
+    };
+    if opts.negative {
+        let negatives = negate(colors, opts.gamma, opts.colorspace);
+        indices.into_iter().map(|n| negatives[n]).collect()
+    } else {
+        indices
+    }
+}
+
+// Quantizes every pixel, giving the color to emit for it along with what that color looks like
+fn colorize(img: RgbImage, opts: &RenderOptions) -> Vec<(Colour, [u8; 3])> {
+    // Only matching against neutral colors keeps the error from dithering any tint back in, as long
+    // as there are any to match against
+    let grays_only = opts.grayscale && opts.palette.has_grays();
+    let usable = |color: &[u8; 3]| !grays_only || is_neutral(color);
+    let indexed = |img, colors: &[[u8; 3]], skip| {
+        let candidates = (skip..colors.len()).filter(|&n| usable(&colors[n])).collect::<Vec<_>>();
+        let indices = quantize(img, &candidates.iter().map(|&n| colors[n]).collect::<Vec<_>>(), opts);
+        // Map the indices we got back to where they are in the palette
+        indices.into_iter().map(|n| (Colour::Fixed(candidates[n] as u8), colors[candidates[n]])).collect()
+    };
+    match opts.palette {
+        // Cut off the first 16 indices since users tend to customize those, unless they're all the
+        // terminal has
+        Palette::Indexed(colors) => indexed(img, colors, if colors.len() > 16 { 16 } else { 0 }),
+        Palette::Custom(ref colors) => indexed(img, colors, 0),
+        Palette::Rgb(ref colors) => {
+            let colors = colors.iter().cloned().filter(usable).collect::<Vec<_>>();
+            let indices = quantize(img, &colors, opts);
+            indices.into_iter().map(|n| (Colour::RGB(colors[n][0], colors[n][1], colors[n][2]), colors[n])).collect()
+        },
+        Palette::Truecolor => {
+            let invert = |n: u8| if opts.negative { 255 - n } else { n };
+            img.pixels()
+                .map(|p| [invert(p.data[0]), invert(p.data[1]), invert(p.data[2])])
+                .map(|[r, g, b]| (Colour::RGB(r, g, b), [r, g, b]))
+                .collect()
+        },
+    }
+}
+pub fn render(img: RgbImage, opts: &RenderOptions) -> Vec<Vec<ANSIString<'static>>> {
+    let w = img.width() as usize;
+    match opts.glyphs {
+        Glyphs::HalfBlocks => render_lines(colorize(img, opts).into_iter().map(|(colour, _)| colour), w),
+        Glyphs::FullBlocks => render_full(colorize(img, opts).into_iter().map(|(colour, _)| colour), w),
+        Glyphs::Blended => render_solid(colorize(blend_rows(&img), opts).into_iter().map(|(colour, _)| colour), w),
+        Glyphs::Quadrants => render_quadrants(&colorize(img, opts), w),
+        Glyphs::Braille => render_braille(&img, opts.threshold, opts.luma),
+        Glyphs::Ascii => render_ascii(&img, &opts.charset, opts.luma),
+        Glyphs::Hex => render_hex(&colorize(img, opts), w),
+        Glyphs::Indices => render_indices(&colorize(img, opts), &opts.palette, w),
+    }
+}
+pub fn render_lines<I: Iterator<Item = Colour>>(colors: I, w: usize) -> Vec<Vec<ANSIString<'static>>> {
+    let rows = colors.chunks_lazy(w * 2);
+    let mut lines = Vec::new();
+    for mut pair in rows.into_iter() {
+        let upper = pair.by_ref().take(w).collect::<Vec<_>>();
+        let mut lower = pair.take(w).peekable();
+
+        lines.push(if lower.peek().is_some() {
+            lower.zip(upper)
+                .map(|(lower, upper)| lower.on(upper))
+                .map(|c| c.paint("\u{2584}"))
+                .collect::<Vec<_>>()
+        } else {
+            upper.into_iter()
+                .map(|c| c.paint("\u{2580}"))
+                .collect::<Vec<_>>()
+        });
+    }
+
+    lines
+}
+
+// One pixel to a cell, drawn as the background behind a space
+pub fn render_full<I: Iterator<Item = Colour>>(colors: I, w: usize) -> Vec<Vec<ANSIString<'static>>> {
+    colors.chunks_lazy(w).into_iter()
+        .map(|row| row.map(|c| Style::new().on(c).paint(" ")).collect())
+        .collect()
+}
+
+// Averages each pair of rows into one, leaving a leftover last row as it is
+fn blend_rows(img: &RgbImage) -> RgbImage {
+    let (w, h) = img.dimensions();
+    RgbImage::from_fn(w, h.div_ceil(2), |x, y| {
+        let upper = img.get_pixel(x, y * 2).data;
+        let lower = if y * 2 + 1 < h { img.get_pixel(x, y * 2 + 1).data } else { upper };
+        Rgb { data: [0, 1, 2].map(|n| ((upper[n] as u16 + lower[n] as u16).div_ceil(2)) as u8) }
+    })
+}
+
+// One pixel to a cell, drawn as a full block in the foreground
+pub fn render_solid<I: Iterator<Item = Colour>>(colors: I, w: usize) -> Vec<Vec<ANSIString<'static>>> {
+    colors.chunks_lazy(w).into_iter()
+        .map(|row| row.map(|c| c.paint("\u{2588}")).collect())
+        .collect()
+}
+
+// The quadrant characters, indexed by which of the upper left, upper right, lower left and lower
+// right quadrants (from the highest bit down) are drawn in the foreground color
+static QUADRANTS: [&str; 16] = [
+    " ", "\u{2597}", "\u{2596}", "\u{2584}", "\u{259d}", "\u{2590}", "\u{259e}", "\u{259f}",
+    "\u{2598}", "\u{259a}", "\u{258c}", "\u{2599}", "\u{2580}", "\u{259c}", "\u{259b}", "\u{2588}",
+];
+
+pub fn render_quadrants(colors: &[(Colour, [u8; 3])], w: usize) -> Vec<Vec<ANSIString<'static>>> {
+    // Every cell covers two pixels across, rounding up in case the width is odd
+    let cols = w.div_ceil(2);
+    let h = colors.len() / w;
+    let mut lines = Vec::new();
+    for y in (0..h).step_by(2) {
+        let line = (0..cols).map(|col| {
+            // Quadrants past the edge of the image are left out
+            let quadrants = [(0, 0), (1, 0), (0, 1), (1, 1)].iter()
+                .map(|&(dx, dy)| (col * 2 + dx, y + dy))
+                .map(|(x, y)| if x < w && y < h { Some(colors[x + y * w]) } else { None })
+                .collect::<Vec<_>>();
+            let present = quadrants.iter().filter_map(|&quadrant| quadrant).collect::<Vec<_>>();
+
+            // The two most common colors, preferring whichever comes first when they're as common
+            let count = |rgb: [u8; 3]| present.iter().filter(|&&(_, c)| c == rgb).count();
+            let mut ranked = present.clone();
+            ranked.sort_by_key(|&(_, rgb)| Reverse(count(rgb)));
+            let fg = ranked[0];
+            let bg = ranked.iter().cloned().find(|&(_, rgb)| rgb != fg.1).unwrap_or(fg);
+
+            // Everything else goes to whichever of them is closer
+            let distance = |a: [u8; 3], b: [u8; 3]| a.iter().zip(&b).map(|(&a, &b)| (a as i32 - b as i32).pow(2)).sum::<i32>();
+            let mask = quadrants.iter().fold(0, |mask, quadrant| {
+                let in_fg = quadrant.is_some_and(|(_, rgb)| distance(rgb, fg.1) <= distance(rgb, bg.1));
+                mask << 1 | in_fg as usize
+            });
+            if quadrants[2].is_none() {
+                // A leftover row only covers the upper half, so leave the lower half alone
+                let glyph = match mask >> 2 {
+                    0b11 => "\u{2580}",
+                    0b10 => "\u{2598}",
+                    _ => "\u{259d}",
+                };
+                fg.0.paint(glyph)
+            } else {
+                fg.0.on(bg.0).paint(QUADRANTS[mask])
+            }
+        }).collect::<Vec<_>>();
+        lines.push(line);
+    }
+
+    lines
+}
+// The bit each dot of a Braille cell sets, by row and then column
+static BRAILLE_DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+pub fn render_braille(img: &RgbImage, threshold: u8, weights: Luma) -> Vec<Vec<ANSIString<'static>>> {
+    let (w, h) = img.dimensions();
+    let mut lines = Vec::new();
+    for y in (0..h).step_by(4) {
+        let line = (0..w.div_ceil(2)).map(|col| {
+            // Dots past the edge of the image are left off
+            let bits = iproduct!(0..4, 0..2)
+                .filter(|&(dy, dx)| col * 2 + dx < w && y + dy < h)
+                .filter(|&(dy, dx)| luma(img.get_pixel(col * 2 + dx, y + dy).data, weights) >= threshold as f32)
+                .fold(0, |bits, (dy, dx)| bits | BRAILLE_DOTS[dy as usize][dx as usize]);
+            ANSIString::from(char::from_u32(0x2800 + bits).unwrap().to_string())
+        }).collect::<Vec<_>>();
+        lines.push(line);
+    }
+
+    lines
+}
+pub fn render_ascii(img: &RgbImage, charset: &str, weights: Luma) -> Vec<Vec<ANSIString<'static>>> {
+    // Characters run from darkest to brightest
+    let ramp = charset.chars().collect::<Vec<_>>();
+    let (w, h) = img.dimensions();
+    (0..h)
+        .map(|y| (0..w).map(|x| {
+            let level = (luma(img.get_pixel(x, y).data, weights) / 255.0 * (ramp.len() - 1) as f32).round() as usize;
+            ANSIString::from(ramp[level].to_string())
+        }).collect())
+        .collect()
+}
+// The color picked for every pixel as hex, space separated, for seeing exactly what dithering did
+pub fn render_hex(colors: &[(Colour, [u8; 3])], w: usize) -> Vec<Vec<ANSIString<'static>>> {
+    colors.chunks(w)
+        .map(|row| row.iter().enumerate().map(|(x, &(_, [r, g, b]))| {
+            ANSIString::from(format!("{}{:02x}{:02x}{:02x}", if x > 0 { " " } else { "" }, r, g, b))
+        }).collect())
+        .collect()
+}
+// The palette index picked for every pixel, space separated, for comparing renders exactly.
+// Truecolor has no palette to index, so its colors come out packed into one number instead.
+pub fn render_indices(colors: &[(Colour, [u8; 3])], palette: &Palette, w: usize) -> Vec<Vec<ANSIString<'static>>> {
+    let index = |&(colour, rgb): &(Colour, [u8; 3])| match (colour, palette) {
+        (Colour::Fixed(n), _) => n as u32,
+        (_, Palette::Rgb(colors)) => colors.iter().position(|&c| c == rgb).unwrap_or(0) as u32,
+        _ => (rgb[0] as u32) << 16 | (rgb[1] as u32) << 8 | rgb[2] as u32,
+    };
+    colors.chunks(w)
+        .map(|row| row.iter().enumerate().map(|(x, color)| {
+            ANSIString::from(format!("{}{}", if x > 0 { " " } else { "" }, index(color)))
+        }).collect())
+        .collect()
+}
+/// Lays out every color of a terminal palette as a cell labeled with its index and hex value. The
+/// first 16 go eight to a row like the usual normal and bright colors, and the rest six to a row,
+/// matching the sides of the color cube.
+pub fn render_palette(colors: &[[u8; 3]]) -> Vec<Vec<ANSIString<'static>>> {
+    let cell = |n: usize| {
+        let [r, g, b] = colors[n];
+        // Dark text on light colors and light text on dark ones, so every label can be read
+        let text = if luma(colors[n], Luma::Rec709) > 128.0 { Colour::Black } else { Colour::White };
+        text.on(Colour::Fixed(n as u8)).paint(format!(" {:3} {:02x}{:02x}{:02x} ", n, r, g, b))
+    };
+    let first = colors.len().min(16);
+    (0..first).collect::<Vec<_>>().chunks(8)
+        .chain((first..colors.len()).collect::<Vec<_>>().chunks(6))
+        .map(|row| row.iter().map(|&n| cell(n)).collect())
+        .collect()
+}
+/// Surrounds rendered lines with cells of a fill color, `[top, right, bottom, left]` cells deep.
+pub fn pad_lines(lines: Vec<Vec<ANSIString<'static>>>, pad: [u16; 4], fill: [u8; 3], opts: &RenderOptions) -> Vec<Vec<ANSIString<'static>>> {
+    let [top, right, bottom, left] = pad.map(|n| n as usize);
+    let cell = match opts.glyphs {
+        // These are drawn without any color, so blank cells are as close as they get
+        Glyphs::Braille | Glyphs::Ascii | Glyphs::Hex | Glyphs::Indices => ANSIString::from(" "),
+        Glyphs::FullBlocks => {
+            let (colour, _) = colorize(RgbImage::from_pixel(1, 1, Rgb { data: fill }), opts)[0];
+            Style::new().on(colour).paint(" ")
+        },
+        Glyphs::Blended => {
+            let (colour, _) = colorize(RgbImage::from_pixel(1, 1, Rgb { data: fill }), opts)[0];
+            colour.paint("\u{2588}")
+        },
+        Glyphs::HalfBlocks | Glyphs::Quadrants => {
+            let (colour, _) = colorize(RgbImage::from_pixel(1, 1, Rgb { data: fill }), opts)[0];
+            colour.on(colour).paint("\u{2584}")
+        },
+    };
+    let cols = lines.iter().map(Vec::len).max().unwrap_or(0);
+    let blank = || vec![cell.clone(); left + cols + right];
+    let padded = lines.into_iter().map(|line| {
+        let fill_right = right + cols - line.len();
+        iter::repeat_n(cell.clone(), left).chain(line).chain(iter::repeat_n(cell.clone(), fill_right)).collect()
+    });
+    iter::repeat_with(blank).take(top).chain(padded).chain(iter::repeat_with(blank).take(bottom)).collect()
+}
+/// Indents rendered lines with plain spaces to center them across `cols` columns.
+pub fn center_lines(lines: Vec<Vec<ANSIString<'static>>>, cols: usize) -> Vec<Vec<ANSIString<'static>>> {
+    let width = lines.iter().map(Vec::len).max().unwrap_or(0);
+    let indent = ANSIString::from(" ".repeat(cols.saturating_sub(width) / 2));
+    lines.into_iter().map(|line| iter::once(indent.clone()).chain(line).collect()).collect()
+}
+/// Cuts every line off at `cols` columns, where a terminal that narrow would otherwise wrap it.
+pub fn truncate_lines(lines: Vec<Vec<ANSIString<'static>>>, cols: usize) -> Vec<Vec<ANSIString<'static>>> {
+    lines.into_iter().map(|line| split_line(line, cols).swap_remove(0)).collect()
+}
+/// Breaks every line wider than `cols` columns into as many lines as it takes.
+pub fn wrap_lines(lines: Vec<Vec<ANSIString<'static>>>, cols: usize) -> Vec<Vec<ANSIString<'static>>> {
+    lines.into_iter().flat_map(|line| split_line(line, cols)).collect()
+}
+// Each piece keeps the style it was cut from, so it still gets its own reset at the end
+fn split_line(line: Vec<ANSIString<'static>>, cols: usize) -> Vec<Vec<ANSIString<'static>>> {
+    let cols = cols.max(1);
+    let mut pieces = vec![Vec::new()];
+    let mut used = 0;
+    for cell in line {
+        let width = cell.chars().count();
+        if used + width <= cols {
+            used += width;
+            pieces.last_mut().unwrap().push(cell);
+            continue;
+        }
+        let style = *cell.style_ref();
+        let mut text = String::new();
+        for c in cell.chars() {
+            if used == cols {
+                if !text.is_empty() {
+                    pieces.last_mut().unwrap().push(style.paint(text));
+                    text = String::new();
+                }
+                pieces.push(Vec::new());
+                used = 0;
+            }
+            text.push(c);
+            used += 1;
+        }
+        pieces.last_mut().unwrap().push(style.paint(text));
+    }
+    pieces
+}
+/// Lays rendered images out in a grid `cols` images across, with a blank column between images
+/// and a blank line between rows of them.
+pub fn montage(tiles: Vec<Vec<Vec<ANSIString<'static>>>>, cols: usize) -> Vec<Vec<ANSIString<'static>>> {
+    let mut lines = Vec::new();
+    let mut tiles = tiles.into_iter().peekable();
+    while tiles.peek().is_some() {
+        let row = tiles.by_ref().take(cols).collect::<Vec<_>>();
+        let widths = row.iter().map(|tile| tile.iter().map(Vec::len).max().unwrap_or(0)).collect::<Vec<_>>();
+        let height = row.iter().map(Vec::len).max().unwrap_or(0);
+        if !lines.is_empty() {
+            lines.push(Vec::new());
+        }
+        for y in 0..height {
+            let mut line = Vec::new();
+            for (i, (tile, &width)) in row.iter().zip(&widths).enumerate() {
+                if i > 0 {
+                    line.push(ANSIString::from(" "));
+                }
+                // Shorter lines and tiles are filled out with spaces to keep everything lined up
+                let cells = tile.get(y).map_or(&[][..], |cells| &cells[..]);
+                line.extend(cells.iter().cloned());
+                if cells.len() < width {
+                    line.push(ANSIString::from(" ".repeat(width - cells.len())));
+                }
+            }
+            lines.push(line);
+        }
+    }
+
+    lines
+}
+pub fn window_lines(lines: Vec<Vec<ANSIString>>, [row, col, rows, cols]: [usize; 4]) -> Vec<Vec<ANSIString>> {
+    lines.into_iter()
+        .skip(row)
+        .take(rows)
+        .map(|line| line.into_iter().skip(col).take(cols).collect())
+        .collect()
+}
+
+fn stage<F: FnOnce(RgbImage) -> RgbImage>(name: &str, img: RgbImage, f: F) -> RgbImage {
+    let (w, h) = img.dimensions();
+    let start = Instant::now();
+    let img = f(img);
+    trace!("{}: {}x{} -> {}x{} in {:?}", name, w, h, img.width(), img.height(), start.elapsed());
+    img
+}
+
+pub fn write_lines<W: Write>(out: &mut W, lines: &[Vec<ANSIString>]) -> io::Result<()> {
+    for line in lines {
+        writeln!(out, "{}", ANSIStrings(line))?;
+    }
+    out.flush()
+}
+pub fn lines_to_string(lines: &[Vec<ANSIString>]) -> String {
+    let mut out = Vec::new();
+    write_lines(&mut out, lines).expect("Writing to memory can't fail");
+    String::from_utf8(out).expect("Rendered output is always UTF-8")
+}
+
+#[derive(Clone, Copy)]
+pub enum Glyphs {
+    // Upper and lower halves of each cell, for twice the vertical resolution
+    HalfBlocks,
+    // Whole cells, for a chunkier look on terminals that leave gaps between half blocks
+    FullBlocks,
+    // Whole cells colored with the average of the two pixels half blocks would show in them
+    Blended,
+    // Quadrants of each cell, for twice the resolution both ways but only two colors per cell
+    Quadrants,
+    // Braille dots, two across and four down in each cell, set wherever the image is bright enough
+    Braille,
+    // Plain characters picked by brightness, one pixel to a cell
+    Ascii,
+    // The hex color picked for each pixel of the half blocks, as plain text
+    Hex,
+    // The palette index picked for each pixel of the half blocks, as plain text
+    Indices,
+}
+
+/// How an image gets turned into terminal output. Leaving both `width` and `height` unset fits
+/// the image to the terminal.
+#[derive(Clone)]
+pub struct RenderOptions<'a> {
+    pub width: Option<Dimension>,
+    pub height: Option<Dimension>,
+    // Terminal rows to leave free when fitting the image, e.g. for a prompt
+    pub reserve_rows: u16,
+    // How many times taller than they're wide the terminal's cells are
+    pub cell_aspect: f32,
+    pub filter: FilterType,
+    // Pick every pixel out of the image whole at any size, so only colors already in it come out
+    pub preserve_palette: bool,
+    // Times to halve the image before the final resize
+    pub downscale_steps: u16,
+    // Error diffusion kernel to dither with, empty to skip dithering
+    pub kernel: Vec<(i32, i32, i32, i32)>,
+    // Skip dithering altogether for plain nearest color matching, ignoring the kernel
+    pub no_dither: bool,
+    // The size of the Bayer matrix to dither with instead of diffusing error
+    pub bayer: Option<u32>,
+    // How much of each pixel's error gets diffused, from none of it at 0 to all of it at 1
+    pub strength: f32,
+    // Work through every other row right to left, with the kernel mirrored
+    pub serpentine: bool,
+    pub palette: Palette<'a>,
+    // The gamma to linearize colors with before matching them against the palette
+    pub gamma: f32,
+    pub colorspace: ColorSpace,
+    // Degrees to turn the image clockwise by, in steps of 90
+    pub rotate: u16,
+    pub autocrop: bool,
+    pub dejpeg: bool,
+    pub equalize: bool,
+    pub lut: Option<Lut>,
+    pub brightness: i16,
+    pub contrast: f32,
+    pub grayscale: bool,
+    // How the monochrome modes and the adjustments working on brightness weigh each channel
+    pub luma: Luma,
+    pub invert: bool,
+    // Mirror the image left to right and top to bottom
+    pub flip_h: bool,
+    pub flip_v: bool,
+    pub glyphs: Glyphs,
+    // The brightness a pixel needs to get a Braille dot
+    pub threshold: u8,
+    // Characters to draw in, from darkest to brightest
+    pub charset: String,
+    pub negative: bool,
+    // Threads to match colors with, each taking its own band of rows, when no error gets diffused
+    pub threads: usize,
+}
+
+impl Default for RenderOptions<'static> {
+    fn default() -> RenderOptions<'static> {
+        RenderOptions {
+            width: None,
+            height: None,
+            reserve_rows: 0,
+            cell_aspect: 2.0,
+            filter: FilterType::Nearest,
+            preserve_palette: false,
+            downscale_steps: 0,
+            kernel: JARVIS_KERNEL.to_vec(),
+            no_dither: false,
+            bayer: None,
+            strength: 1.0,
+            serpentine: false,
+            palette: Palette::Indexed(&ANSI_COLORS),
+            gamma: 2.2,
+            colorspace: ColorSpace::Rgb,
+            rotate: 0,
+            autocrop: false,
+            dejpeg: false,
+            equalize: false,
+            lut: None,
+            brightness: 0,
+            contrast: 1.0,
+            grayscale: false,
+            luma: Luma::Rec709,
+            invert: false,
+            flip_h: false,
+            flip_v: false,
+            glyphs: Glyphs::HalfBlocks,
+            threshold: 128,
+            charset: String::from(" .:-=+*#%@"),
+            negative: false,
+            threads: 1,
+        }
+    }
+}
+
+/// Applies the steps that change the image before it is sized, so its aspect ratio can be taken
+/// from the result.
+pub fn prepare(img: RgbImage, opts: &RenderOptions) -> RgbImage {
+    let img = match opts.rotate {
+        90 => stage("rotate", img, |img| imageops::rotate90(&img)),
+        180 => stage("rotate", img, |img| imageops::rotate180(&img)),
+        270 => stage("rotate", img, |img| imageops::rotate270(&img)),
+        _ => img,
+    };
+    let img = if opts.autocrop { stage("autocrop", img, |img| autocrop(img, opts.luma)) } else { img };
+    if opts.dejpeg { stage("dejpeg", img, |img| dejpeg(&img)) } else { img }
+}
+
+/// Resizes a prepared image to `w` columns by `h` pixel rows and renders it, one line per row of
+/// cells.
+pub fn render_sized(img: RgbImage, w: u16, h: u16, opts: &RenderOptions) -> Vec<Vec<ANSIString<'static>>> {
+    // Quadrants fit two pixels across into every column, and Braille twice as many down as well,
+    // while full blocks and characters only fit one pixel in a cell
+    let (w_cols, w, h) = match opts.glyphs {
+        Glyphs::HalfBlocks | Glyphs::Blended => (w, w, h),
+        Glyphs::FullBlocks => (w, w, h.div_ceil(2)),
+        Glyphs::Quadrants => (w, w.saturating_mul(2), h),
+        Glyphs::Braille => (w, w.saturating_mul(2), h.saturating_mul(2)),
+        Glyphs::Ascii => (w, w, h.div_ceil(2)),
+        Glyphs::Hex | Glyphs::Indices => (w, w, h),
+    };
+    let resized = resize(img, w as u32, h as u32, opts);
+
+    let start = Instant::now();
+    let lines = render(resized, opts);
+    trace!("dither: {}x{} -> {}x{} cells in {:?}", w, h, w_cols, lines.len(), start.elapsed());
+    lines
+}
+
+fn point_sample(img: &RgbImage, w: u32, h: u32) -> RgbImage {
+    let (src_w, src_h) = img.dimensions();
+    RgbImage::from_fn(w, h, |x, y| *img.get_pixel(x * src_w / w, y * src_h / h))
+}
+
+// Resizes an image to exactly `w` by `h` pixels and applies every adjustment made to the result
+fn resize(img: RgbImage, w: u32, h: u32, opts: &RenderOptions) -> RgbImage {
+    let img = if opts.preserve_palette {
+        img
+    } else {
+        stage("downscale", img, |img| halve(img, opts.downscale_steps, w, h))
+    };
+    let resized = stage("resize", img, |img| match opts.filter {
+        // The box filter behind nearest sampling averages pixels together when shrinking, making
+        // up colors the image never had, so they get picked out whole to keep to its own colors
+        _ if opts.preserve_palette => point_sample(&img, w, h),
+        // It also comes up empty when enlarging, so pick pixels out directly then as well
+        FilterType::Nearest if w > img.width() || h > img.height() => point_sample(&img, w, h),
+        filter => imageops::resize(&img, w, h, filter),
+    });
+    let resized = if opts.equalize {
+        stage("equalize", resized, |mut img| { equalize(&mut img, opts.luma); img })
+    } else {
+        resized
+    };
+    let resized = match opts.lut {
+        Some(ref lut) => stage("lut", resized, |mut img| { apply_lut(&mut img, lut); img }),
+        None => resized,
+    };
+
+    let resized = if opts.brightness != 0 || opts.contrast != 1.0 {
+        stage("adjust", resized, |mut img| { adjust(&mut img, opts.brightness, opts.contrast); img })
+    } else {
+        resized
+    };
+    let resized = if opts.grayscale {
+        stage("grayscale", resized, |mut img| { grayscale(&mut img, opts.luma); img })
+    } else {
+        resized
+    };
+    let resized = if opts.invert {
+        stage("invert", resized, |mut img| { invert(&mut img); img })
+    } else {
+        resized
+    };
+    let resized = if opts.flip_h {
+        stage("flip-h", resized, |img| imageops::flip_horizontal(&img))
+    } else {
+        resized
+    };
+    if opts.flip_v {
+        stage("flip-v", resized, |img| imageops::flip_vertical(&img))
+    } else {
+        resized
+    }
+}
+
+/// Pixels across and down that a terminal cell is taken to be for graphics protocols, which draw
+/// in pixels rather than cells.
+pub const CELL_PIXELS: [u32; 2] = [10, 20];
+
+/// Resizes a prepared image to fill `w` columns by `h` pixel rows of half blocks and encodes it as
+/// a Sixel image at the resolution the terminal actually has to draw with.
+pub fn render_sixel(img: RgbImage, w: u16, h: u16, opts: &RenderOptions) -> String {
+    // Every half block is as wide as a cell and half as tall
+    let (w, h) = (w as u32 * CELL_PIXELS[0], (h as u32 * CELL_PIXELS[1]).div_ceil(2));
+    let resized = resize(img, w, h, opts);
+
+    let start = Instant::now();
+    // Sixel color registers are set by the image itself, so none of the terminal's colors need to
+    // be avoided. There are only so many of them, so anything bigger falls back to the 256 colors.
+    let grays_only = opts.grayscale && opts.palette.has_grays();
+    let usable = |color: &[u8; 3]| !grays_only || is_neutral(color);
+    let colors = match opts.palette {
+        Palette::Indexed(colors) => colors,
+        Palette::Custom(ref colors) | Palette::Rgb(ref colors) if colors.len() <= 256 => colors,
+        _ => &ANSI_COLORS[..],
+    }.iter().cloned().filter(usable).collect::<Vec<_>>();
+    let indices = quantize(resized, &colors, opts);
+
+    let mut out = String::new();
+    // Leave pixels no color is drawn in alone, then give the image's size in pixels
+    out.push_str(&format!("\x1bP0;1q\"1;1;{};{}", w, h));
+    for (n, color) in colors.iter().enumerate() {
+        let [r, g, b] = color.map(|c| (c as u32 * 100 + 127) / 255);
+        out.push_str(&format!("#{};2;{};{};{}", n, r, g, b));
+    }
+    // Each band of six rows draws every color in it in turn, a column at a time
+    for top in (0..h).step_by(6) {
+        let rows = (h - top).min(6);
+        let mut used = indices[(top * w) as usize..((top + rows) * w) as usize].to_vec();
+        used.sort_unstable();
+        used.dedup();
+        for (i, &color) in used.iter().enumerate() {
+            if i > 0 {
+                out.push('$');
+            }
+            out.push_str(&format!("#{}", color));
+            let sixels = (0..w).map(|x| {
+                let bits = (0..rows).filter(|&y| indices[((top + y) * w + x) as usize] == color)
+                    .fold(0, |bits, y| bits | 1 << y);
+                (63 + bits) as u8 as char
+            });
+            for (sixel, run) in sixels.group_by_lazy(|&c| c).into_iter().map(|(c, run)| (c, run.count())) {
+                if run > 3 {
+                    out.push_str(&format!("!{}{}", run, sixel));
+                } else {
+                    out.extend(iter::repeat_n(sixel, run));
+                }
+            }
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\\n");
+    trace!("sixel: {}x{} pixels in {:?}", w, h, start.elapsed());
+    out
+}
+
+/// Renders an image to a string of escape codes ready to be printed, or `None` if it should be
+/// fit to the terminal and the terminal's size is unknown.
+pub fn render_image(img: &RgbImage, opts: &RenderOptions) -> Option<String> {
+    let img = prepare(img.clone(), opts);
+    let aspect = img.width() as f32 / img.height() as f32;
+    let (w, h) = determine_size(aspect, opts.width, opts.height, opts.reserve_rows, opts.cell_aspect, termsize::get().as_ref())?;
+    trace!("size: {}x{} at aspect {}", w, h, aspect);
+    Some(lines_to_string(&render_sized(img, w, h, opts)))
+}
+
+// Resizes an image to the pixels behind `w` columns by `h` pixel rows of half blocks and encodes
+// it as a PNG, for the protocols that take whole image files. Negating has no palette to work with,
+// so it flips the colors like truecolor output does.
+fn encode_png(img: RgbImage, w: u16, h: u16, opts: &RenderOptions) -> Vec<u8> {
+    let (w, h) = (w as u32 * CELL_PIXELS[0], (h as u32 * CELL_PIXELS[1]).div_ceil(2));
+    let mut resized = resize(img, w, h, opts);
+    if opts.negative {
+        invert(&mut resized);
+    }
+
+    let start = Instant::now();
+    let mut out = Vec::new();
+    png::PNGEncoder::new(&mut out).encode(&resized.into_raw(), w, h, ColorType::RGB(8))
+        .expect("Encoding to memory can't fail");
+    trace!("png: {}x{} pixels into {} bytes in {:?}", w, h, out.len(), start.elapsed());
+    out
+}
+
+/// Resizes a prepared image to fill `w` columns by `h` pixel rows of half blocks and shows it with
+/// iTerm2's inline image protocol, in full color without any dithering.
+pub fn render_iterm(img: RgbImage, w: u16, h: u16, opts: &RenderOptions) -> String {
+    let png = encode_png(img, w, h, opts);
+    format!("\x1b]1337;File=inline=1;size={};width={};height={};preserveAspectRatio=0:{}\x07\n",
+            png.len(), w, h.div_ceil(2), STANDARD.encode(&png))
+}
+
+/// Resizes a prepared image to fill `w` columns by `h` pixel rows of half blocks and shows it at the
+/// cursor with Kitty's graphics protocol, in full color without any dithering.
+pub fn render_kitty(img: RgbImage, w: u16, h: u16, opts: &RenderOptions) -> String {
+    let data = STANDARD.encode(encode_png(img, w, h, opts));
+    // The data has to be sent in chunks, each saying whether any more follow it
+    let chunks = data.as_bytes().chunks(4096).collect::<Vec<_>>();
+    let mut out = String::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = if i + 1 < chunks.len() { 1 } else { 0 };
+        let chunk = str::from_utf8(chunk).expect("Base64 is always ASCII");
+        if i == 0 {
+            out.push_str(&format!("\x1b_Gf=100,a=T,c={},r={},m={};{}\x1b\\", w, h.div_ceil(2), more, chunk));
+        } else {
+            out.push_str(&format!("\x1b_Gm={};{}\x1b\\", more, chunk));
+        }
+    }
+    out.push('\n');
+    out
+}
+
+pub static ANSI_COLORS: [[u8; 3]; 256] = [
+    [0x00, 0x00, 0x00], [0x80, 0x00, 0x00], [0x00, 0x80, 0x00],
+    [0x80, 0x80, 0x00], [0x00, 0x00, 0x80], [0x80, 0x00, 0x80],
+    [0x00, 0x80, 0x80], [0xc0, 0xc0, 0xc0], [0x80, 0x80, 0x80],
+    [0xff, 0x00, 0x00], [0x00, 0xff, 0x00], [0xff, 0xff, 0x00],
+    [0x00, 0x00, 0xff], [0xff, 0x00, 0xff], [0x00, 0xff, 0xff],
+    [0xff, 0xff, 0xff], [0x00, 0x00, 0x00], [0x00, 0x00, 0x5f],
+    [0x00, 0x00, 0x87], [0x00, 0x00, 0xaf], [0x00, 0x00, 0xd7],
+    [0x00, 0x00, 0xff], [0x00, 0x5f, 0x00], [0x00, 0x5f, 0x5f],
+    [0x00, 0x5f, 0x87], [0x00, 0x5f, 0xaf], [0x00, 0x5f, 0xd7],
+    [0x00, 0x5f, 0xff], [0x00, 0x87, 0x00], [0x00, 0x87, 0x5f],
+    [0x00, 0x87, 0x87], [0x00, 0x87, 0xaf], [0x00, 0x87, 0xd7],
+    [0x00, 0x87, 0xff], [0x00, 0xaf, 0x00], [0x00, 0xaf, 0x5f],
+    [0x00, 0xaf, 0x87], [0x00, 0xaf, 0xaf], [0x00, 0xaf, 0xd7],
+    [0x00, 0xaf, 0xff], [0x00, 0xd7, 0x00], [0x00, 0xd7, 0x5f],
+    [0x00, 0xd7, 0x87], [0x00, 0xd7, 0xaf], [0x00, 0xd7, 0xd7],
+    [0x00, 0xd7, 0xff], [0x00, 0xff, 0x00], [0x00, 0xff, 0x5f],
+    [0x00, 0xff, 0x87], [0x00, 0xff, 0xaf], [0x00, 0xff, 0xd7],
+    [0x00, 0xff, 0xff], [0x5f, 0x00, 0x00], [0x5f, 0x00, 0x5f],
+    [0x5f, 0x00, 0x87], [0x5f, 0x00, 0xaf], [0x5f, 0x00, 0xd7],
+    [0x5f, 0x00, 0xff], [0x5f, 0x5f, 0x00], [0x5f, 0x5f, 0x5f],
+    [0x5f, 0x5f, 0x87], [0x5f, 0x5f, 0xaf], [0x5f, 0x5f, 0xd7],
+    [0x5f, 0x5f, 0xff], [0x5f, 0x87, 0x00], [0x5f, 0x87, 0x5f],
+    [0x5f, 0x87, 0x87], [0x5f, 0x87, 0xaf], [0x5f, 0x87, 0xd7],
+    [0x5f, 0x87, 0xff], [0x5f, 0xaf, 0x00], [0x5f, 0xaf, 0x5f],
+    [0x5f, 0xaf, 0x87], [0x5f, 0xaf, 0xaf], [0x5f, 0xaf, 0xd7],
+    [0x5f, 0xaf, 0xff], [0x5f, 0xd7, 0x00], [0x5f, 0xd7, 0x5f],
+    [0x5f, 0xd7, 0x87], [0x5f, 0xd7, 0xaf], [0x5f, 0xd7, 0xd7],
+    [0x5f, 0xd7, 0xff], [0x5f, 0xff, 0x00], [0x5f, 0xff, 0x5f],
+    [0x5f, 0xff, 0x87], [0x5f, 0xff, 0xaf], [0x5f, 0xff, 0xd7],
+    [0x5f, 0xff, 0xff], [0x87, 0x00, 0x00], [0x87, 0x00, 0x5f],
+    [0x87, 0x00, 0x87], [0x87, 0x00, 0xaf], [0x87, 0x00, 0xd7],
+    [0x87, 0x00, 0xff], [0x87, 0x5f, 0x00], [0x87, 0x5f, 0x5f],
+    [0x87, 0x5f, 0x87], [0x87, 0x5f, 0xaf], [0x87, 0x5f, 0xd7],
+    [0x87, 0x5f, 0xff], [0x87, 0x87, 0x00], [0x87, 0x87, 0x5f],
+    [0x87, 0x87, 0x87], [0x87, 0x87, 0xaf], [0x87, 0x87, 0xd7],
+    [0x87, 0x87, 0xff], [0x87, 0xaf, 0x00], [0x87, 0xaf, 0x5f],
+    [0x87, 0xaf, 0x87], [0x87, 0xaf, 0xaf], [0x87, 0xaf, 0xd7],
+    [0x87, 0xaf, 0xff], [0x87, 0xd7, 0x00], [0x87, 0xd7, 0x5f],
+    [0x87, 0xd7, 0x87], [0x87, 0xd7, 0xaf], [0x87, 0xd7, 0xd7],
+    [0x87, 0xd7, 0xff], [0x87, 0xff, 0x00], [0x87, 0xff, 0x5f],
+    [0x87, 0xff, 0x87], [0x87, 0xff, 0xaf], [0x87, 0xff, 0xd7],
+    [0x87, 0xff, 0xff], [0xaf, 0x00, 0x00], [0xaf, 0x00, 0x5f],
+    [0xaf, 0x00, 0x87], [0xaf, 0x00, 0xaf], [0xaf, 0x00, 0xd7],
+    [0xaf, 0x00, 0xff], [0xaf, 0x5f, 0x00], [0xaf, 0x5f, 0x5f],
+    [0xaf, 0x5f, 0x87], [0xaf, 0x5f, 0xaf], [0xaf, 0x5f, 0xd7],
+    [0xaf, 0x5f, 0xff], [0xaf, 0x87, 0x00], [0xaf, 0x87, 0x5f],
+    [0xaf, 0x87, 0x87], [0xaf, 0x87, 0xaf], [0xaf, 0x87, 0xd7],
+    [0xaf, 0x87, 0xff], [0xaf, 0xaf, 0x00], [0xaf, 0xaf, 0x5f],
+    [0xaf, 0xaf, 0x87], [0xaf, 0xaf, 0xaf], [0xaf, 0xaf, 0xd7],
+    [0xaf, 0xaf, 0xff], [0xaf, 0xd7, 0x00], [0xaf, 0xd7, 0x5f],
+    [0xaf, 0xd7, 0x87], [0xaf, 0xd7, 0xaf], [0xaf, 0xd7, 0xd7],
+    [0xaf, 0xd7, 0xff], [0xaf, 0xff, 0x00], [0xaf, 0xff, 0x5f],
+    [0xaf, 0xff, 0x87], [0xaf, 0xff, 0xaf], [0xaf, 0xff, 0xd7],
+    [0xaf, 0xff, 0xff], [0xd7, 0x00, 0x00], [0xd7, 0x00, 0x5f],
+    [0xd7, 0x00, 0x87], [0xd7, 0x00, 0xaf], [0xd7, 0x00, 0xd7],
+    [0xd7, 0x00, 0xff], [0xd7, 0x5f, 0x00], [0xd7, 0x5f, 0x5f],
+    [0xd7, 0x5f, 0x87], [0xd7, 0x5f, 0xaf], [0xd7, 0x5f, 0xd7],
+    [0xd7, 0x5f, 0xff], [0xd7, 0x87, 0x00], [0xd7, 0x87, 0x5f],
+    [0xd7, 0x87, 0x87], [0xd7, 0x87, 0xaf], [0xd7, 0x87, 0xd7],
+    [0xd7, 0x87, 0xff], [0xd7, 0xaf, 0x00], [0xd7, 0xaf, 0x5f],
+    [0xd7, 0xaf, 0x87], [0xd7, 0xaf, 0xaf], [0xd7, 0xaf, 0xd7],
+    [0xd7, 0xaf, 0xff], [0xd7, 0xd7, 0x00], [0xd7, 0xd7, 0x5f],
+    [0xd7, 0xd7, 0x87], [0xd7, 0xd7, 0xaf], [0xd7, 0xd7, 0xd7],
+    [0xd7, 0xd7, 0xff], [0xd7, 0xff, 0x00], [0xd7, 0xff, 0x5f],
+    [0xd7, 0xff, 0x87], [0xd7, 0xff, 0xaf], [0xd7, 0xff, 0xd7],
+    [0xd7, 0xff, 0xff], [0xff, 0x00, 0x00], [0xff, 0x00, 0x5f],
+    [0xff, 0x00, 0x87], [0xff, 0x00, 0xaf], [0xff, 0x00, 0xd7],
+    [0xff, 0x00, 0xff], [0xff, 0x5f, 0x00], [0xff, 0x5f, 0x5f],
+    [0xff, 0x5f, 0x87], [0xff, 0x5f, 0xaf], [0xff, 0x5f, 0xd7],
+    [0xff, 0x5f, 0xff], [0xff, 0x87, 0x00], [0xff, 0x87, 0x5f],
+    [0xff, 0x87, 0x87], [0xff, 0x87, 0xaf], [0xff, 0x87, 0xd7],
+    [0xff, 0x87, 0xff], [0xff, 0xaf, 0x00], [0xff, 0xaf, 0x5f],
+    [0xff, 0xaf, 0x87], [0xff, 0xaf, 0xaf], [0xff, 0xaf, 0xd7],
+    [0xff, 0xaf, 0xff], [0xff, 0xd7, 0x00], [0xff, 0xd7, 0x5f],
+    [0xff, 0xd7, 0x87], [0xff, 0xd7, 0xaf], [0xff, 0xd7, 0xd7],
+    [0xff, 0xd7, 0xff], [0xff, 0xff, 0x00], [0xff, 0xff, 0x5f],
+    [0xff, 0xff, 0x87], [0xff, 0xff, 0xaf], [0xff, 0xff, 0xd7],
+    [0xff, 0xff, 0xff], [0x08, 0x08, 0x08], [0x12, 0x12, 0x12],
+    [0x1c, 0x1c, 0x1c], [0x26, 0x26, 0x26], [0x30, 0x30, 0x30],
+    [0x3a, 0x3a, 0x3a], [0x44, 0x44, 0x44], [0x4e, 0x4e, 0x4e],
+    [0x58, 0x58, 0x58], [0x60, 0x60, 0x60], [0x66, 0x66, 0x66],
+    [0x76, 0x76, 0x76], [0x80, 0x80, 0x80], [0x8a, 0x8a, 0x8a],
+    [0x94, 0x94, 0x94], [0x9e, 0x9e, 0x9e], [0xa8, 0xa8, 0xa8],
+    [0xb2, 0xb2, 0xb2], [0xbc, 0xbc, 0xbc], [0xc6, 0xc6, 0xc6],
+    [0xd0, 0xd0, 0xd0], [0xda, 0xda, 0xda], [0xe4, 0xe4, 0xe4],
+    [0xee, 0xee, 0xee],
+];
+
+pub static RXVT_COLORS: [[u8; 3]; 88] = [
+    [0x00, 0x00, 0x00], [0x80, 0x00, 0x00], [0x00, 0x80, 0x00],
+    [0x80, 0x80, 0x00], [0x00, 0x00, 0x80], [0x80, 0x00, 0x80],
+    [0x00, 0x80, 0x80], [0xc0, 0xc0, 0xc0], [0x80, 0x80, 0x80],
+    [0xff, 0x00, 0x00], [0x00, 0xff, 0x00], [0xff, 0xff, 0x00],
+    [0x00, 0x00, 0xff], [0xff, 0x00, 0xff], [0x00, 0xff, 0xff],
+    [0xff, 0xff, 0xff], [0x00, 0x00, 0x00], [0x00, 0x00, 0x8b],
+    [0x00, 0x00, 0xcd], [0x00, 0x00, 0xff], [0x00, 0x8b, 0x00],
+    [0x00, 0x8b, 0x8b], [0x00, 0x8b, 0xcd], [0x00, 0x8b, 0xff],
+    [0x00, 0xcd, 0x00], [0x00, 0xcd, 0x8b], [0x00, 0xcd, 0xcd],
+    [0x00, 0xcd, 0xff], [0x00, 0xff, 0x00], [0x00, 0xff, 0x8b],
+    [0x00, 0xff, 0xcd], [0x00, 0xff, 0xff], [0x8b, 0x00, 0x00],
+    [0x8b, 0x00, 0x8b], [0x8b, 0x00, 0xcd], [0x8b, 0x00, 0xff],
+    [0x8b, 0x8b, 0x00], [0x8b, 0x8b, 0x8b], [0x8b, 0x8b, 0xcd],
+    [0x8b, 0x8b, 0xff], [0x8b, 0xcd, 0x00], [0x8b, 0xcd, 0x8b],
+    [0x8b, 0xcd, 0xcd], [0x8b, 0xcd, 0xff], [0x8b, 0xff, 0x00],
+    [0x8b, 0xff, 0x8b], [0x8b, 0xff, 0xcd], [0x8b, 0xff, 0xff],
+    [0xcd, 0x00, 0x00], [0xcd, 0x00, 0x8b], [0xcd, 0x00, 0xcd],
+    [0xcd, 0x00, 0xff], [0xcd, 0x8b, 0x00], [0xcd, 0x8b, 0x8b],
+    [0xcd, 0x8b, 0xcd], [0xcd, 0x8b, 0xff], [0xcd, 0xcd, 0x00],
+    [0xcd, 0xcd, 0x8b], [0xcd, 0xcd, 0xcd], [0xcd, 0xcd, 0xff],
+    [0xcd, 0xff, 0x00], [0xcd, 0xff, 0x8b], [0xcd, 0xff, 0xcd],
+    [0xcd, 0xff, 0xff], [0xff, 0x00, 0x00], [0xff, 0x00, 0x8b],
+    [0xff, 0x00, 0xcd], [0xff, 0x00, 0xff], [0xff, 0x8b, 0x00],
+    [0xff, 0x8b, 0x8b], [0xff, 0x8b, 0xcd], [0xff, 0x8b, 0xff],
+    [0xff, 0xcd, 0x00], [0xff, 0xcd, 0x8b], [0xff, 0xcd, 0xcd],
+    [0xff, 0xcd, 0xff], [0xff, 0xff, 0x00], [0xff, 0xff, 0x8b],
+    [0xff, 0xff, 0xcd], [0xff, 0xff, 0xff], [0x2e, 0x2e, 0x2e],
+    [0x5c, 0x5c, 0x5c], [0x73, 0x73, 0x73], [0x8b, 0x8b, 0x8b],
+    [0xa2, 0xa2, 0xa2], [0xb9, 0xb9, 0xb9], [0xd0, 0xd0, 0xd0],
+    [0xe7, 0xe7, 0xe7],
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn render_to_string(img: RgbImage, palette: Palette<'static>) -> String {
+        lines_to_string(&render(img, &RenderOptions { palette, ..RenderOptions::default() }))
+    }
+
+    #[test]
+    fn render_pairs_rows_into_half_blocks() {
+        let img = RgbImage::from_fn(1, 2, |_, y| Rgb { data: if y == 0 { [0xff, 0x00, 0x00] } else { [0x00, 0x00, 0xff] } });
+        // The lower pixel is the foreground of the lower half block, the upper one its background
+        assert_eq!(render_to_string(img, Palette::Indexed(&ANSI_COLORS)), "\x1b[48;5;196;38;5;21m\u{2584}\x1b[0m\n");
+    }
+
+    #[test]
+    fn render_full_blocks_one_pixel_to_a_cell() {
+        let img = RgbImage::from_fn(2, 2, |_, y| Rgb { data: if y == 0 { [0xff, 0x00, 0x00] } else { [0x00, 0x00, 0xff] } });
+        let opts = RenderOptions { glyphs: Glyphs::FullBlocks, ..RenderOptions::default() };
+        assert_eq!(lines_to_string(&render(img, &opts)), "\x1b[48;5;196m  \x1b[0m\n\x1b[48;5;21m  \x1b[0m\n");
+    }
+
+    #[test]
+    fn render_blended_averages_each_pair_of_rows() {
+        let rows = [[0xff, 0x00, 0x00], [0x00, 0x00, 0xff], [0x00, 0xff, 0x00]];
+        let img = RgbImage::from_fn(1, 3, |_, y| Rgb { data: rows[y as usize] });
+        let opts = RenderOptions { palette: Palette::Truecolor, glyphs: Glyphs::Blended, ..RenderOptions::default() };
+        // The leftover last row has nothing to be averaged with
+        assert_eq!(lines_to_string(&render(img, &opts)), "\x1b[38;2;128;0;128m\u{2588}\x1b[0m\n\x1b[38;2;0;255;0m\u{2588}\x1b[0m\n");
+    }
+
+    #[test]
+    fn render_uses_upper_half_blocks_for_a_leftover_row() {
+        let img = RgbImage::from_pixel(2, 1, Rgb { data: [0xff, 0x00, 0x00] });
+        assert_eq!(render_to_string(img, Palette::Indexed(&ANSI_COLORS)), "\x1b[38;5;196m\u{2580}\u{2580}\x1b[0m\n");
+    }
+
+    #[test]
+    fn render_skips_the_customizable_colors() {
+        // Pure red is also color 9, but users tend to change that one
+        let img = RgbImage::from_pixel(1, 2, Rgb { data: [0xff, 0x00, 0x00] });
+        let rendered = render_to_string(img, Palette::Indexed(&ANSI_COLORS));
+        assert!(rendered.contains("38;5;196"));
+        assert!(!rendered.contains("38;5;9m"));
+    }
+
+    #[test]
+    fn render_uses_the_basic_colors_when_they_are_all_there_is() {
+        let img = RgbImage::from_pixel(1, 2, Rgb { data: [0xff, 0x00, 0x00] });
+        let rendered = render_to_string(img, Palette::Indexed(&ANSI_COLORS[..16]));
+        assert!(rendered.contains("38;5;9m"));
+    }
+
+    #[test]
+    fn render_emits_truecolor_for_rgb_palettes() {
+        let img = RgbImage::from_pixel(1, 2, Rgb { data: [0xf0, 0x10, 0x10] });
+        let rendered = render_to_string(img, Palette::Rgb(cube_palette(1)));
+        assert!(rendered.contains("48;2;255;0;0;38;2;255;0;0m"));
+    }
+
+    #[test]
+    fn render_passes_truecolor_through_untouched() {
+        let img = RgbImage::from_fn(1, 2, |_, y| Rgb { data: [0x12, 0x34, y as u8] });
+        assert_eq!(render_to_string(img, Palette::Truecolor), "\x1b[48;2;18;52;0;38;2;18;52;1m\u{2584}\x1b[0m\n");
+    }
+
+    #[test]
+    fn has_grays_only_counts_neutral_colors() {
+        assert!(Palette::Indexed(&ANSI_COLORS).has_grays());
+        assert!(Palette::Custom(vec![[0xff, 0x00, 0x00], [0x80, 0x80, 0x80]]).has_grays());
+        assert!(!Palette::Custom(vec![[0xff, 0x00, 0x00], [0x00, 0xff, 0x00], [0x00, 0x00, 0xff]]).has_grays());
+        assert!(!Palette::Rgb(vec![[0x10, 0x10, 0x11]]).has_grays());
+    }
+
+    #[test]
+    fn render_grayscale_uses_every_color_when_the_palette_has_no_grays() {
+        let img = RgbImage::from_fn(2, 2, |x, _| Rgb { data: [x as u8 * 200; 3] });
+        let colors = vec![[0xff, 0x00, 0x00], [0x00, 0xff, 0x00], [0x00, 0x00, 0xff]];
+        let opts = RenderOptions { palette: Palette::Custom(colors), grayscale: true, ..RenderOptions::default() };
+        assert_eq!(render(img, &opts).len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Matching needs at least one color")]
+    fn matcher_needs_a_color() {
+        Matcher::new(&[], 2.2, ColorSpace::Rgb);
+    }
+
+    #[test]
+    fn negate_maps_colors_to_the_nearest_inverse() {
+        assert_eq!(negate(&[[0x00, 0x00, 0x00], [0xff, 0xff, 0xff], [0x80, 0x00, 0x00]], 2.2, ColorSpace::Rgb), vec![1, 0, 1]);
+    }
+
+    #[test]
+    fn apply_lut_interpolates_between_grid_points() {
+        // Swaps red and blue
+        let lut = parse_lut("LUT_3D_SIZE 2\n\
+                             0 0 0\n0 0 1\n0 1 0\n0 1 1\n\
+                             1 0 0\n1 0 1\n1 1 0\n1 1 1\n").unwrap();
+        let mut img = RgbImage::from_pixel(1, 1, Rgb { data: [0xff, 0x80, 0x00] });
+        apply_lut(&mut img, &lut);
+        assert_eq!(img.get_pixel(0, 0).data, [0x00, 0x80, 0xff]);
+    }
+
+    #[test]
+    fn dither_breaks_ties_toward_darker_colors() {
+        // The pixel is exactly halfway between both palette entries, in either order
+        let pixel = RgbImage::from_pixel(1, 1, Rgb { data: [0x40, 0x40, 0x40] });
+        assert_eq!(dither(pixel.clone(), &[[0x00, 0x00, 0x00], [0x80, 0x80, 0x80]], &JARVIS_KERNEL, 1.0, false, 1.0, ColorSpace::Rgb), vec![0]);
+        assert_eq!(dither(pixel, &[[0x80, 0x80, 0x80], [0x00, 0x00, 0x00]], &JARVIS_KERNEL, 1.0, false, 1.0, ColorSpace::Rgb), vec![1]);
+    }
+
+    #[test]
+    fn dither_breaks_remaining_ties_toward_lower_indices() {
+        // Duplicate entries are equally distant and equally bright
+        let pixel = RgbImage::from_pixel(1, 1, Rgb { data: [0x40, 0x40, 0x40] });
+        assert_eq!(dither(pixel, &[[0xff, 0x00, 0x00], [0x80, 0x80, 0x80], [0x80, 0x80, 0x80]], &JARVIS_KERNEL, 1.0, false, 1.0, ColorSpace::Rgb), vec![1]);
+    }
+
+    #[test]
+    fn dither_without_strength_picks_the_nearest_colors() {
+        let img = RgbImage::from_fn(16, 4, |x, y| Rgb { data: [(x * 16) as u8, (y * 64) as u8, 0x80] });
+        assert_eq!(dither(img.clone(), &ANSI_COLORS, &JARVIS_KERNEL, 0.0, false, 2.2, ColorSpace::Rgb), nearest(img, &ANSI_COLORS, 2.2, ColorSpace::Rgb));
+    }
+
+    #[test]
+    fn dither_keeps_the_edges_as_bright_as_the_interior() {
+        // Any one gray can happen to put a sparse pattern's gaps along an edge, so it takes the
+        // average over a range of them to show a drift
+        let (width, height) = (32, 128);
+        for kernel in [&FLOYD_STEINBERG_KERNEL[..], &JARVIS_KERNEL[..], &STUCKI_KERNEL[..], &SIERRA_KERNEL[..], &SIERRA_LITE_KERNEL[..]] {
+            let (mut left, mut right) = (0.0, 0.0);
+            let grays = (0x10..0xf0).step_by(8).collect::<Vec<u8>>();
+            for &gray in &grays {
+                let img = RgbImage::from_pixel(width, height, Rgb { data: [gray, gray, gray] });
+                let indices = dither(img, &[[0x00, 0x00, 0x00], [0xff, 0xff, 0xff]], kernel, 1.0, false, 1.0, ColorSpace::Rgb);
+                let mean = |columns: &[u32]| {
+                    let white = iproduct!(columns, 0..height).filter(|&(&x, y)| indices[(x + y * width) as usize] == 1).count();
+                    white as f32 / (columns.len() as u32 * height) as f32
+                };
+                let interior = mean(&(4..width - 4).collect::<Vec<_>>());
+                left += (mean(&[0]) - interior).abs() / grays.len() as f32;
+                right += (mean(&[width - 1]) - interior).abs() / grays.len() as f32;
+            }
+            assert!(left < 0.1 && right < 0.1, "edges are off by {} on the left and {} on the right", left, right);
+        }
+    }
+
+    #[test]
+    fn dither_keeps_error_past_the_right_edge_out_of_the_next_row() {
+        // Both grays round down to black, and the first would tip the second over to white if its
+        // error wrapped around onto it
+        let img = RgbImage::from_fn(3, 2, |x, y| Rgb { data: if (x, y) == (1, 0) || (x, y) == (0, 1) { [0x70; 3] } else { [0x00; 3] } });
+        assert_eq!(dither(img, &[[0x00, 0x00, 0x00], [0xff, 0xff, 0xff]], &[(2, 0, 1, 1)], 1.0, false, 1.0, ColorSpace::Rgb), vec![0; 6]);
+    }
+
+    #[test]
+    fn nearest_matches_dithering_without_a_kernel() {
+        let img = RgbImage::from_fn(16, 4, |x, y| Rgb { data: [(x * 16) as u8, (y * 64) as u8, 0x80] });
+        assert_eq!(nearest(img.clone(), &ANSI_COLORS, 2.2, ColorSpace::Rgb), dither(img, &ANSI_COLORS, &[], 1.0, false, 2.2, ColorSpace::Rgb));
+    }
+
+    #[test]
+    fn matcher_finds_the_same_colors_as_searching_everything() {
+        // Coarse colors so plenty of them tie, including exact duplicates
+        let mut seed = 1u32;
+        let colors = (0..600).map(|_| [0, 1, 2].map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8 & 0xf0
+        })).collect::<Vec<_>>();
+        for &space in &[ColorSpace::Rgb, ColorSpace::Lab] {
+            let matcher = Matcher::new(&colors, 2.2, space);
+            let linear = &matcher.linear;
+            for (r, g, b) in iproduct!((0..256).step_by(24), (0..256).step_by(24), (0..256).step_by(24)) {
+                let pixel = [linear[r], linear[g], linear[b]];
+                let position = Matcher::position(space, &pixel);
+                let expected = (0..colors.len()).min_by_key(|&idx| {
+                    let distance = position.iter().zip(&matcher.positions[idx]).map(|(a, b)| (a - b) * (a - b)).sum::<i64>();
+                    let col = colors[idx];
+                    (distance, 299 * col[0] as u32 + 587 * col[1] as u32 + 114 * col[2] as u32, idx)
+                }).unwrap();
+                assert_eq!(matcher.nearest_linear(&pixel), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn matcher_bins_only_answer_for_colors_they_wholly_belong_to() {
+        // Stepping off the bin edges, and through levels no sRGB pixel has, as error diffusion does,
+        // over and over until the bins get worked out
+        let matcher = Matcher::new(&ANSI_COLORS, 2.2, ColorSpace::Rgb);
+        for _ in 0..BIN_VISITS + 2 {
+            for (r, g, b) in iproduct!((0..65536).step_by(2731), (0..65536).step_by(2039), (0..65536).step_by(1777)) {
+                assert_eq!(matcher.nearest_linear(&[r, g, b]), matcher.search_all(&[r, g, b]));
+            }
+        }
+        let states = matcher.bins.iter().map(|state| state.load(Ordering::Relaxed)).collect::<Vec<_>>();
+        assert!(states.contains(&MIXED_BIN));
+        assert!(states.iter().any(|&state| state > BIN_VISITS && state != MIXED_BIN));
+    }
+
+    #[test]
+    fn dither_serpentine_runs_odd_rows_right_to_left() {
+        // The first row matches exactly, so the second gets no error from it and can be checked alone
+        let level = |x: u32| 40 + 30 * x as u8;
+        let img = RgbImage::from_fn(7, 2, |x, y| Rgb { data: [if y == 0 { 0 } else { level(x) }; 3] });
+        let flipped = RgbImage::from_fn(7, 1, |x, _| Rgb { data: [level(6 - x); 3] });
+        let colors = [[0x00, 0x00, 0x00], [0xff, 0xff, 0xff]];
+        let serpentine = dither(img.clone(), &colors, &FLOYD_STEINBERG_KERNEL, 1.0, true, 1.0, ColorSpace::Rgb);
+        let mut backwards = dither(flipped, &colors, &FLOYD_STEINBERG_KERNEL, 1.0, false, 1.0, ColorSpace::Rgb);
+        backwards.reverse();
+        assert_eq!(serpentine[7..], backwards[..]);
+        assert_ne!(serpentine, dither(img, &colors, &FLOYD_STEINBERG_KERNEL, 1.0, false, 1.0, ColorSpace::Rgb));
+    }
+
+    #[test]
+    fn nearest_color_picks_the_closest_entry_and_the_lower_index_on_ties() {
+        // Pure red and black are in the palette twice, as 9 and 196 and as 0 and 16
+        assert_eq!(nearest_color(&[255, 0, 0], &ANSI_COLORS), 9);
+        assert_eq!(nearest_color(&[0, 0, 0], &ANSI_COLORS), 0);
+        assert_eq!(nearest_color(&[250, 4, 4], &ANSI_COLORS), 9);
+        assert_eq!(nearest_color(&[0x5f, 0x87, 0xaf], &ANSI_COLORS), 67);
+        let img = RgbImage::from_fn(16, 16, |x, y| Rgb { data: [x as u8 * 16, y as u8 * 16, 200] });
+        let expected = img.pixels().map(|pixel| nearest_color(&pixel.data, &ANSI_COLORS)).collect::<Vec<_>>();
+        assert_eq!(nearest(img, &ANSI_COLORS, 2.2, ColorSpace::Rgb), expected);
+        // Asking about another palette doesn't get the last one's answer
+        assert_eq!(nearest_color(&[255, 0, 0], &[[0, 0, 255], [255, 0, 0]]), 1);
+        assert_eq!(nearest_color(&[255, 0, 0], &[[255, 0, 0], [0, 0, 255]]), 0);
+    }
+
+    #[test]
+    fn bayer_matrices_visit_every_threshold_once() {
+        assert_eq!((0..4).map(|y| (0..4).map(|x| bayer(4, x, y)).collect::<Vec<_>>()).collect::<Vec<_>>(),
+                   [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]]);
+        let mut eight = iproduct!(0..8, 0..8).map(|(x, y)| bayer(8, x, y)).collect::<Vec<_>>();
+        eight.sort_unstable();
+        assert_eq!(eight, (0..64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn ordered_dithering_mixes_a_flat_color() {
+        // Halfway between black and white in linear light, so half the pixels go each way
+        let img = RgbImage::from_pixel(4, 4, Rgb { data: [0x80, 0x80, 0x80] });
+        let indices = ordered(img, &[[0x00, 0x00, 0x00], [0xff, 0xff, 0xff]], 4, 1.0, ColorSpace::Rgb);
+        assert_eq!(indices.iter().filter(|&&n| n == 1).count(), 8);
+    }
+
+    #[test]
+    fn render_image_sizes_to_the_given_dimensions() {
+        let img = RgbImage::from_pixel(8, 8, Rgb { data: [0xff, 0x00, 0x00] });
+        let opts = RenderOptions { width: Some(Dimension::Cells(4)), height: Some(Dimension::Cells(1)), ..RenderOptions::default() };
+        // A height of one row is two pixels tall, paired into a single line of half blocks
+        let out = render_image(&img, &opts).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert_eq!(out.matches('\u{2584}').count(), 4);
+    }
+
+    #[test]
+    fn render_quadrants_splits_cells_between_two_colors() {
+        let img = RgbImage::from_fn(2, 2, |x, _| Rgb { data: if x == 0 { [0xff, 0x00, 0x00] } else { [0x00, 0x00, 0xff] } });
+        let opts = RenderOptions { palette: Palette::Truecolor, glyphs: Glyphs::Quadrants, ..RenderOptions::default() };
+        // The left half in red over the right half in blue
+        assert_eq!(lines_to_string(&render(img, &opts)), "\x1b[48;2;0;0;255;38;2;255;0;0m\u{258c}\x1b[0m\n");
+    }
+
+    #[test]
+    fn render_sixel_draws_bands_of_six_pixel_rows() {
+        let img = RgbImage::from_pixel(2, 2, Rgb { data: [0xff, 0x00, 0x00] });
+        let opts = RenderOptions { palette: Palette::Rgb(vec![[0x00, 0x00, 0x00], [0xff, 0x00, 0x00]]), ..RenderOptions::default() };
+        // One cell is 10 pixels across and 20 down, which is three full bands and a third of one
+        assert_eq!(render_sixel(img, 1, 2, &opts),
+                   "\x1bP0;1q\"1;1;10;20#0;2;0;0;0#1;2;100;0;0#1!10~-#1!10~-#1!10~-#1!10B-\x1b\\\n");
+    }
+
+    #[test]
+    fn render_iterm_hints_the_size_in_cells() {
+        let img = RgbImage::from_pixel(2, 2, Rgb { data: [0xff, 0x00, 0x00] });
+        let out = render_iterm(img, 3, 4, &RenderOptions::default());
+        let (header, data) = out.split_at(out.find(':').unwrap());
+        assert!(header.ends_with(";width=3;height=2;preserveAspectRatio=0"));
+        assert!(STANDARD.decode(data[1..].trim_end_matches("\x07\n")).unwrap().starts_with(b"\x89PNG"));
+    }
+
+    #[test]
+    fn render_kitty_sends_the_image_in_chunks() {
+        // Noise compresses badly enough to need more than one chunk
+        let img = RgbImage::from_fn(100, 100, |x, y| {
+            let n = (x * 100 + y).wrapping_mul(2654435761);
+            Rgb { data: [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8] }
+        });
+        let out = render_kitty(img, 10, 10, &RenderOptions::default());
+        let chunks = out.trim_end().split("\x1b\\").filter(|chunk| !chunk.is_empty()).collect::<Vec<_>>();
+        assert!(chunks.len() > 1);
+        assert!(chunks[0].starts_with("\x1b_Gf=100,a=T,c=10,r=5,m=1;"));
+        assert!(chunks[1..chunks.len() - 1].iter().all(|chunk| chunk.starts_with("\x1b_Gm=1;")));
+        assert!(chunks[chunks.len() - 1].starts_with("\x1b_Gm=0;"));
+    }
+
+    #[test]
+    fn render_braille_sets_dots_for_bright_pixels() {
+        // A bright left column over a dark right one, with the bottom row past the edge
+        let img = RgbImage::from_fn(2, 3, |x, _| Rgb { data: if x == 0 { [0xff, 0xff, 0xff] } else { [0x00, 0x00, 0x00] } });
+        assert_eq!(lines_to_string(&render_braille(&img, 128, Luma::Rec709)), "\u{2807}\n");
+    }
+
+    #[test]
+    fn luma_weighs_the_channels_as_asked() {
+        let green = [0x00, 0xff, 0x00];
+        assert_eq!(luma(green, Luma::Rec601).round(), 150.0);
+        assert_eq!(luma(green, Luma::Rec709).round(), 182.0);
+        assert_eq!(luma(green, Luma::Average).round(), 85.0);
+        assert_eq!(luma([0xff; 3], Luma::Average).round(), 255.0);
+    }
+
+    #[test]
+    fn render_ascii_picks_characters_by_brightness() {
+        let img = RgbImage::from_fn(3, 1, |x, _| Rgb { data: [[0x00, 0x80, 0xff][x as usize]; 3] });
+        assert_eq!(lines_to_string(&render_ascii(&img, " .:-=+*#%@", Luma::Rec709)), " +@\n");
+    }
+
+    #[test]
+    fn render_hex_prints_every_pixel() {
+        let img = RgbImage::from_fn(2, 2, |x, _| Rgb { data: if x == 0 { [0xff, 0x00, 0x00] } else { [0x00, 0x00, 0xff] } });
+        let opts = RenderOptions { glyphs: Glyphs::Hex, ..RenderOptions::default() };
+        assert_eq!(lines_to_string(&render(img, &opts)), "ff0000 0000ff\nff0000 0000ff\n");
+    }
+
+    #[test]
+    fn render_indices_prints_every_pixel() {
+        let img = RgbImage::from_fn(2, 2, |x, _| Rgb { data: if x == 0 { [0xff, 0x00, 0x00] } else { [0x00, 0x00, 0xff] } });
+        let opts = RenderOptions { glyphs: Glyphs::Indices, ..RenderOptions::default() };
+        assert_eq!(lines_to_string(&render(img.clone(), &opts)), "196 21\n196 21\n");
+        let opts = RenderOptions { glyphs: Glyphs::Indices, palette: Palette::Rgb(vec![[0x00, 0x00, 0xff], [0xff, 0x00, 0x00]]), ..RenderOptions::default() };
+        assert_eq!(lines_to_string(&render(img, &opts)), "1 0\n1 0\n");
+    }
+
+    #[test]
+    fn render_palette_labels_every_color() {
+        let lines = render_palette(&ANSI_COLORS);
+        assert_eq!(lines.len(), 2 + 36 + 4);
+        assert_eq!(lines.iter().map(|line| line.len()).sum::<usize>(), 256);
+        assert_eq!(lines_to_string(&[lines[2][..1].to_vec()]), "\x1b[48;5;16;37m  16 000000 \x1b[0m\n");
+        assert_eq!(lines_to_string(&[lines[41][5..].to_vec()]), "\x1b[48;5;255;30m 255 eeeeee \x1b[0m\n");
+    }
+
+    #[test]
+    fn letterbox_pads_both_sides_to_the_aspect_ratio() {
+        let img = RgbImage::from_pixel(2, 2, Rgb { data: [0xff, 0x00, 0x00] });
+        let boxed = letterbox(img, 2.0, [0x00, 0x00, 0xff]);
+        assert_eq!(boxed.dimensions(), (4, 2));
+        let row = (0..4).map(|x| boxed.get_pixel(x, 0).data).collect::<Vec<_>>();
+        assert_eq!(row, [[0x00, 0x00, 0xff], [0xff, 0x00, 0x00], [0xff, 0x00, 0x00], [0x00, 0x00, 0xff]]);
+    }
+
+    #[test]
+    fn crop_to_aspect_cuts_both_sides_to_the_aspect_ratio() {
+        let img = RgbImage::from_fn(4, 2, |x, _| Rgb { data: [x as u8, 0x00, 0x00] });
+        let cropped = crop_to_aspect(img, 1.0);
+        assert_eq!(cropped.dimensions(), (2, 2));
+        assert_eq!((0..2).map(|x| cropped.get_pixel(x, 0).data[0]).collect::<Vec<_>>(), [1, 2]);
+        let img = RgbImage::from_pixel(2, 8, Rgb { data: [0xff, 0x00, 0x00] });
+        assert_eq!(crop_to_aspect(img, 0.5).dimensions(), (2, 4));
+    }
+
+    #[test]
+    fn resize_preserving_the_palette_only_picks_out_colors_from_the_image() {
+        let red_blue = [[0xff, 0x00, 0x00], [0x00, 0x00, 0xff]];
+        let img = RgbImage::from_fn(8, 8, |x, y| Rgb { data: red_blue[((x + y) % 2) as usize] });
+        let opts = RenderOptions { preserve_palette: true, downscale_steps: 2, ..RenderOptions::default() };
+        for &(w, h) in &[(3, 3), (5, 2), (8, 8), (11, 9)] {
+            let resized = resize(img.clone(), w, h, &opts);
+            assert_eq!(resized.dimensions(), (w, h));
+            assert!(resized.pixels().all(|pixel| red_blue.contains(&pixel.data)));
+        }
+    }
+
+    #[test]
+    fn quantize_comes_out_the_same_on_any_number_of_threads() {
+        let img = RgbImage::from_fn(13, 21, |x, y| Rgb { data: [x as u8 * 19, y as u8 * 12, 90] });
+        for opts in [RenderOptions { no_dither: true, ..RenderOptions::default() },
+                     RenderOptions { bayer: Some(4), ..RenderOptions::default() },
+                     RenderOptions::default()] {
+            let alone = quantize(img.clone(), &ANSI_COLORS, &opts);
+            assert_eq!(quantize(img.clone(), &ANSI_COLORS, &RenderOptions { threads: 4, ..opts }), alone);
+        }
+    }
+
+    #[test]
+    fn render_sized_flips_the_resized_image() {
+        let img = RgbImage::from_fn(2, 2, |x, y| Rgb { data: [if x == 0 { 0xff } else { 0x00 }, 0x00, if y == 0 { 0xff } else { 0x00 }] });
+        let opts = RenderOptions { palette: Palette::Truecolor, flip_h: true, flip_v: true, ..RenderOptions::default() };
+        // Enlarged so every pixel is picked out whole. Black ends up in the top left over blue, and
+        // red in the top right over magenta.
+        assert_eq!(lines_to_string(&render_sized(img, 4, 4, &opts)),
+                   "\x1b[48;2;0;0;0;38;2;0;0;0m\u{2584}\u{2584}\x1b[48;2;255;0;0;38;2;255;0;0m\u{2584}\u{2584}\x1b[0m\n\
+                    \x1b[48;2;0;0;255;38;2;0;0;255m\u{2584}\u{2584}\x1b[48;2;255;0;255;38;2;255;0;255m\u{2584}\u{2584}\x1b[0m\n");
+    }
+
+    #[test]
+    fn render_sized_leaves_an_odd_last_row_in_the_upper_halves() {
+        let img = RgbImage::from_pixel(4, 4, Rgb { data: [0xff, 0x00, 0x00] });
+        let opts = RenderOptions { palette: Palette::Truecolor, ..RenderOptions::default() };
+        assert_eq!(lines_to_string(&render_sized(img.clone(), 2, 1, &opts)), "\x1b[38;2;255;0;0m\u{2580}\u{2580}\x1b[0m\n");
+        let lines = render_sized(img.clone(), 2, 3, &opts);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines_to_string(&lines[1..]), "\x1b[38;2;255;0;0m\u{2580}\u{2580}\x1b[0m\n");
+        // Every other way of drawing pairs rows up too, and has to cope with one left over
+        for &glyphs in &[Glyphs::FullBlocks, Glyphs::Blended, Glyphs::Quadrants, Glyphs::Braille, Glyphs::Ascii] {
+            let opts = RenderOptions { glyphs, ..RenderOptions::default() };
+            assert_eq!(render_sized(img.clone(), 2, 1, &opts).len(), 1);
+            assert_eq!(render_sized(img.clone(), 2, 3, &opts).len(), 2);
+        }
+    }
+
+    #[test]
+    fn prepare_rotates_before_sizing() {
+        let img = RgbImage::from_fn(4, 2, |x, _| Rgb { data: if x == 0 { [0xff, 0x00, 0x00] } else { [0x00, 0x00, 0x00] } });
+        let rotated = prepare(img, &RenderOptions { rotate: 90, ..RenderOptions::default() });
+        // The left column turns clockwise into the top row
+        assert_eq!(rotated.dimensions(), (2, 4));
+        assert_eq!(rotated.get_pixel(0, 0).data, [0xff, 0x00, 0x00]);
+        assert_eq!(rotated.get_pixel(1, 0).data, [0xff, 0x00, 0x00]);
+        assert_eq!(rotated.get_pixel(0, 1).data, [0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn pad_lines_surrounds_the_image_with_the_fill_color() {
+        let img = RgbImage::from_pixel(1, 2, Rgb { data: [0xff, 0x00, 0x00] });
+        let opts = RenderOptions { palette: Palette::Truecolor, ..RenderOptions::default() };
+        let padded = pad_lines(render(img, &opts), [1, 0, 0, 2], [0x00, 0x00, 0xff], &opts);
+        let (blue, red) = ("\x1b[48;2;0;0;255;38;2;0;0;255m", "\x1b[48;2;255;0;0;38;2;255;0;0m");
+        assert_eq!(lines_to_string(&padded), format!("{0}\u{2584}\u{2584}\u{2584}\x1b[0m\n{0}\u{2584}\u{2584}{1}\u{2584}\x1b[0m\n", blue, red));
+    }
+
+    #[test]
+    fn center_lines_indents_with_plain_spaces() {
+        let lines = vec![vec![ANSIString::from("a"), ANSIString::from("b")]];
+        assert_eq!(lines_to_string(&center_lines(lines.clone(), 7)), "  ab\n");
+        assert_eq!(lines_to_string(&center_lines(lines, 1)), "ab\n");
+    }
+
+    #[test]
+    fn truncate_and_wrap_lines_cut_at_the_column_count() {
+        let red = Colour::Red.paint("\u{2584}\u{2584}\u{2584}");
+        let lines = vec![vec![ANSIString::from("ab"), red, ANSIString::from("c")]];
+        let cut = Colour::Red.paint("\u{2584}").to_string();
+        assert_eq!(lines_to_string(&truncate_lines(lines.clone(), 3)), format!("ab{}\n", cut));
+        assert_eq!(lines_to_string(&truncate_lines(lines.clone(), 9)), lines_to_string(&lines));
+        let rest = Colour::Red.paint("\u{2584}\u{2584}").to_string();
+        assert_eq!(lines_to_string(&wrap_lines(lines, 3)), format!("ab{}\n{}c\n", cut, rest));
+    }
+
+    #[test]
+    fn montage_lines_tiles_up_in_a_grid() {
+        let tile = |text: &'static str, rows: usize| vec![vec![ANSIString::from(text)]; rows];
+        let grid = montage(vec![tile("a", 2), tile("b", 1), tile("c", 1)], 2);
+        assert_eq!(lines_to_string(&grid), "a b\na  \n\nc\n");
+    }
+
+    #[test]
+    fn composite_shows_the_backdrop_through_transparent_pixels() {
+        let img = RgbaImage::from_fn(2, 1, |x, _| Rgba { data: if x == 0 { [0xff, 0x00, 0x00, 0x00] } else { [0xff, 0x00, 0x00, 0xff] } });
+        let backdrop = RgbImage::from_pixel(2, 1, Rgb { data: [0x12, 0x34, 0x56] });
+        let flattened = composite(&img, &backdrop);
+        assert_eq!(flattened.get_pixel(0, 0).data, [0x12, 0x34, 0x56]);
+        assert_eq!(flattened.get_pixel(1, 0).data, [0xff, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn determine_size_uses_whatever_is_asked_for() {
+        assert_eq!(determine_size(4.0, Some(Dimension::Cells(30)), Some(Dimension::Cells(5)), 0, 2.0, None), Some((30, 10)));
+        assert_eq!(determine_size(2.0, Some(Dimension::Cells(30)), None, 0, 2.0, None), Some((30, 15)));
+        assert_eq!(determine_size(2.0, None, Some(Dimension::Cells(5)), 0, 2.0, None), Some((20, 10)));
+        assert_eq!(determine_size(1.0, Some(Dimension::Cells(30)), None, 0, 2.2, None), Some((30, 27)));
+    }
+
+    #[test]
+    fn determine_size_resolves_percentages_against_the_terminal() {
+        let terminal = Size { rows: 24, cols: 80 };
+        assert_eq!(determine_size(2.0, Some(Dimension::Percent(50)), None, 0, 2.0, Some(&terminal)), Some((40, 20)));
+        assert_eq!(determine_size(2.0, None, Some(Dimension::Percent(50)), 0, 2.0, Some(&terminal)), Some((48, 24)));
+        assert_eq!(determine_size(2.0, Some(Dimension::Percent(50)), None, 0, 2.0, None), None);
+    }
+
+    #[test]
+    fn determine_size_fits_the_terminal() {
+        let terminal = Size { rows: 24, cols: 80 };
+        assert_eq!(determine_size(1.0, None, None, 0, 2.0, None), None);
+        assert_eq!(determine_size(1.0, None, None, 0, 2.0, Some(&terminal)), Some((48, 48)));
+        assert_eq!(determine_size(1.0, None, None, 4, 2.0, Some(&terminal)), Some((40, 40)));
+        // Too wide to fill the height
+        assert_eq!(determine_size(4.0, None, None, 0, 2.0, Some(&terminal)), Some((80, 20)));
+
+        // Terminals with fewer columns than pixel rows fill the width unless that's too tall
+        let terminal = Size { rows: 30, cols: 40 };
+        assert_eq!(determine_size(2.0, None, None, 0, 2.0, Some(&terminal)), Some((40, 20)));
+        assert_eq!(determine_size(0.5, None, None, 0, 2.0, Some(&terminal)), Some((30, 60)));
+    }
+
+    #[test]
+    fn determine_size_fits_panoramas_too_wide_to_rescale() {
+        // Filling the height would take more columns than fit in a u16
+        let terminal = Size { rows: 100, cols: 5000 };
+        assert_eq!(determine_size(400.0, None, None, 0, 2.0, Some(&terminal)), Some((5000, 12)));
+    }
+
+    #[test]
+    fn determine_size_saturates_heights_too_tall_to_double() {
+        assert_eq!(determine_size(1.0, Some(Dimension::Cells(30)), Some(Dimension::Cells(40000)), 0, 2.0, None), Some((30, u16::MAX)));
+        let terminal = Size { rows: 40000, cols: 100 };
+        assert_eq!(determine_size(0.001, None, None, 0, 2.0, Some(&terminal)), Some((65, u16::MAX)));
+    }
+
+    #[test]
+    fn clamp_size_keeps_the_shape() {
+        assert_eq!(clamp_size((80, 40), (1000, 2000)), (80, 40));
+        assert_eq!(clamp_size((10000, 1000), (1000, 2000)), (1000, 100));
+        assert_eq!(clamp_size((100, 4000), (1000, 2000)), (50, 2000));
+    }
+
+    #[test]
+    fn clamp_size_never_goes_below_one_cell() {
+        assert_eq!(clamp_size((0, 40), (1000, 2000)), (1, 40));
+        assert_eq!(clamp_size((80, 0), (1000, 2000)), (80, 1));
+        assert_eq!(clamp_size((60000, 1), (1000, 2000)), (1000, 1));
+    }
+
+    #[test]
+    fn extreme_aspect_ratios_still_render_something() {
+        let opts = RenderOptions::default();
+        for &(w, h, width, height) in &[(1, 400, None, Some(Dimension::Cells(10))), (2000, 1, Some(Dimension::Cells(10)), None)] {
+            let img = RgbImage::from_pixel(w, h, Rgb([0x80, 0x28, 0xc8]));
+            let (cols, rows) = determine_size(w as f32 / h as f32, width, height, 0, 2.0, None).unwrap();
+            let lines = render_sized(img.clone(), cols, rows, &opts);
+            assert!(!lines.is_empty() && lines.iter().all(|line| !line.is_empty()));
+            assert!(render_sixel(img.clone(), cols, rows, &opts).contains('#'));
+            assert!(render_kitty(img, cols, rows, &opts).contains(&format!("r={}", rows.div_ceil(2))));
+        }
+    }
+
+    #[test]
+    fn determine_size_never_goes_below_one_cell() {
+        // A 1x400 image 10 rows tall, and a 2000x1 one 10 columns wide
+        assert_eq!(determine_size(1.0 / 400.0, None, Some(Dimension::Cells(10)), 0, 2.0, None), Some((1, 20)));
+        assert_eq!(determine_size(2000.0, Some(Dimension::Cells(10)), None, 0, 2.0, None), Some((10, 1)));
+        let terminal = Size { rows: 24, cols: 80 };
+        assert_eq!(determine_size(1.0 / 4000.0, None, None, 0, 2.0, Some(&terminal)), Some((1, 48)));
+        assert_eq!(determine_size(4000.0, None, None, 0, 2.0, Some(&terminal)), Some((80, 1)));
+    }
+}
@@ -1,291 +1,2316 @@
 extern crate ansi_term;
+extern crate base64;
 extern crate clap;
-extern crate conv;
+extern crate env_logger;
+extern crate exif;
+extern crate gif;
 extern crate image;
-extern crate itertools;
+#[cfg(unix)]
+extern crate libc;
+#[macro_use]
+extern crate log;
+extern crate pic2term;
+extern crate png;
+extern crate serde;
 extern crate termsize;
+extern crate toml;
+#[cfg(feature = "url")]
+extern crate ureq;
 
-use ansi_term::{ANSIStrings, Colour};
-use clap::{App, Arg};
-use conv::{UnwrapOrSaturate, ValueFrom};
-use image::{imageops, FilterType, RgbImage};
-use itertools::Itertools;
-use termsize::Size;
+use ansi_term::{ANSIString, ANSIStrings};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use clap::{App, Arg, ArgMatches};
+use gif::{DisposalMethod, SetParameter};
+use image::{imageops, DynamicImage, FilterType, GenericImage, ImageBuffer, ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+use log::LevelFilter;
+use pic2term::{center_lines, clamp_size, composite, crop_to_aspect, cube_palette, determine_size, gradient, letterbox, lines_to_string, montage, pad_lines,
+               parse_lut, prepare, render_iterm, render_kitty, render_palette, render_sixel, render_sized, truncate_lines, window_lines,
+               wrap_lines, write_lines, ColorSpace, CELL_PIXELS, Dimension, Dither, Glyphs, Luma, Palette, RenderOptions, ANSI_COLORS, RXVT_COLORS};
+use serde::Deserialize;
 
-use std::ops::Add;
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-fn determine_size(aspect: f32, desired_w: Option<u16>, desired_h: Option<u16>) -> Option<(u16, u16)> {
-    // To note, we're outputting with double density vertically due to the
-    // Unicode bottom-half character, so we need to consider that in size
-    // calculations if the user provided a height.
-    let desired_h = desired_h.map(|n| n * 2);
-
-    if let Some(desired_w) = desired_w {
-        if let Some(desired_h) = desired_h {
-            Some((desired_w, desired_h))
-        } else {
-            // Width is known, height is not. Match height to the aspect ratio
-            Some((desired_w, (desired_w as f32 / aspect) as u16))
-        }
-    } else {
-        if let Some(desired_h) = desired_h {
-            // Height is known, width is not. Match width to the aspect ratio
-            Some(((desired_h as f32 * aspect) as u16, desired_h))
-        } else {
-            // Width and height are unknown
-            match termsize::get() {
-                Some(Size { rows: h, cols: w }) => {
-                    // Our terminal is virtually twice as tall as we otherwise believe it to be.
-                    let h = h * 2;
-
-                    // Take the smaller dimension and scale the other to fit
-                    if w < h {
-                        let rescaled_h = (w as f32 / aspect) as u16;
-                        if rescaled_h > h {
-                            let scale = h as f32 / rescaled_h as f32;
-                            Some(((w as f32 * scale) as u16, h))
-                        } else {
-                            Some((w, rescaled_h))
-                        }
-                    } else { // h <= w
-                        let rescaled_w = (h as f32 * aspect) as u16;
-                        if rescaled_w > w {
-                            let scale = w as f32 / rescaled_w as f32;
-                            Some((w, (h as f32 * scale) as u16))
-                        } else {
-                            Some((rescaled_w, h))
-                        }
-                    }
-                },
-                None => None
-            }
-        }
-    }
-}
 fn determine_filter(filter_str: &str) -> FilterType {
     match filter_str {
         "nearest" => FilterType::Nearest,
-        "triangle" => FilterType::Triangle,
+        // auto is settled per file once its size is known, this only stands in for it until then
+        "auto" | "triangle" => FilterType::Triangle,
         "gaussian" => FilterType::Gaussian,
         "catmullrom" => FilterType::CatmullRom,
         "lanczos3" => FilterType::Lanczos3,
         _ => unreachable!(),
     }
 }
+// Pixel art keeps its hard edges when each of its pixels becomes a whole number of them, big
+// reductions need a sharp filter to hold on to their detail, and a soft one leaves the fewest
+// artifacts otherwise
+fn auto_filter(img: &RgbImage, target_w: u32) -> &'static str {
+    let w = img.width();
+    if (w.is_multiple_of(target_w) || target_w.is_multiple_of(w)) && is_pixel_art(img) {
+        "nearest"
+    } else if w >= target_w.saturating_mul(2) {
+        "lanczos3"
+    } else {
+        "triangle"
+    }
+}
+// Pixel art is drawn in a handful of flat colors, where photos have thousands
+fn is_pixel_art(img: &RgbImage) -> bool {
+    let mut colors = HashSet::new();
+    img.pixels().all(|p| {
+        colors.insert(p.data);
+        colors.len() <= 64
+    })
+}
 fn is_u16(s: String) -> Result<(), String> {
     match s.parse::<u16>() {
         Ok(_) => Ok(()),
         Err(e) => Err(e.to_string()),
     }
 }
+fn is_positive(s: String) -> Result<(), String> {
+    match s.parse::<u16>() {
+        Ok(0) => Err(String::from("must be at least 1")),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+fn parse_dimension(s: &str) -> Result<Dimension, String> {
+    let parsed = if let Some(percent) = s.strip_suffix('%') {
+        percent.parse::<u16>().map(Dimension::Percent)
+    } else {
+        s.parse::<u16>().map(Dimension::Cells)
+    };
+    parsed.map_err(|e| e.to_string())
+}
+fn is_dimension(s: String) -> Result<(), String> {
+    parse_dimension(&s).map(|_| ())
+}
+fn parse_color(s: &str) -> Result<[u8; 3], String> {
+    let hex = s.trim_start_matches('#');
+    if hex.len() != 6 {
+        return Err(format!("'{}' is not a color of the form RRGGBB", s));
+    }
+
+    let mut color = [0; 3];
+    for (i, channel) in color.iter_mut().enumerate() {
+        *channel = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+            .map_err(|_| format!("'{}' is not a color of the form RRGGBB", s))?;
+    }
+    Ok(color)
+}
+// A palette file has a color per line, in order starting from index 0. Comments run from a # with
+// a space (or nothing) after it to the end of the line, so colors can still be written as #RRGGBB.
+fn parse_palette(text: &str) -> Result<Vec<[u8; 3]>, String> {
+    let colors = text.lines()
+        .map(|line| {
+            let comment = line.match_indices('#').map(|(at, _)| at)
+                .find(|&at| line[at + 1..].chars().next().is_none_or(char::is_whitespace));
+            &line[..comment.unwrap_or(line.len())]
+        })
+        .enumerate()
+        .filter(|&(_, line)| !line.trim().is_empty())
+        .map(|(n, line)| parse_color(line.trim()).map_err(|e| format!("line {}: {}", n + 1, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    match colors.len() {
+        0 => Err(String::from("there are no colors in it")),
+        1..=256 => Ok(colors),
+        n => Err(format!("it has {} colors, but terminals only have 256", n)),
+    }
+}
+fn is_color(s: String) -> Result<(), String> {
+    parse_color(&s).map(|_| ())
+}
+fn parse_gradient(s: &str) -> Result<([u8; 3], [u8; 3]), String> {
+    let mut colors = s.split(',');
+    match (colors.next(), colors.next(), colors.next()) {
+        (Some(top), Some(bottom), None) => Ok((parse_color(top)?, parse_color(bottom)?)),
+        _ => Err(format!("'{}' is not a gradient of the form RRGGBB,RRGGBB", s)),
+    }
+}
+fn is_gradient(s: String) -> Result<(), String> {
+    parse_gradient(&s).map(|_| ())
+}
+fn is_brightness(s: String) -> Result<(), String> {
+    match s.parse::<i16>() {
+        Ok(-255..=255) => Ok(()),
+        Ok(_) => Err(String::from("the brightness must be between -255 and 255")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+fn is_contrast(s: String) -> Result<(), String> {
+    match s.parse::<f32>() {
+        Ok(contrast) if contrast >= 0.0 && contrast.is_finite() => Ok(()),
+        Ok(_) => Err(String::from("the contrast can't be negative")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+fn is_threads(s: String) -> Result<(), String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err(String::from("at least one thread is needed")),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+fn is_gamma(s: String) -> Result<(), String> {
+    match s.parse::<f32>() {
+        Ok(gamma) if gamma > 0.0 && gamma.is_finite() => Ok(()),
+        Ok(_) => Err(String::from("the gamma must be a positive number")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+fn is_threshold(s: String) -> Result<(), String> {
+    match s.parse::<u8>() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+fn is_charset(s: String) -> Result<(), String> {
+    if s.is_empty() {
+        Err(String::from("at least one character is needed"))
+    } else {
+        Ok(())
+    }
+}
+fn is_strength(s: String) -> Result<(), String> {
+    match s.parse::<f32>() {
+        Ok(strength) if strength.is_nan() => Err(String::from("the strength must be a number")),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+fn is_scale(s: String) -> Result<(), String> {
+    match s.parse::<f32>() {
+        Ok(scale) if scale > 0.0 && scale.is_finite() => Ok(()),
+        Ok(_) => Err(String::from("the scale must be above 0")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+fn is_cell_aspect(s: String) -> Result<(), String> {
+    match s.parse::<f32>() {
+        Ok(ratio) if ratio > 0.0 && ratio.is_finite() => Ok(()),
+        Ok(_) => Err(String::from("the ratio must be above 0")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+fn is_fps(s: String) -> Result<(), String> {
+    match s.parse::<f64>() {
+        Ok(fps) if fps > 0.0 && fps.is_finite() => Ok(()),
+        Ok(_) => Err(String::from("the frame rate must be above 0")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+fn is_montage(s: String) -> Result<(), String> {
+    match s.parse::<u16>() {
+        Ok(0) => Err(String::from("at least one column is needed")),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+fn parse_window(s: &str) -> Result<[usize; 4], String> {
+    let parts = s.split(',').map(str::parse::<u16>).collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("'{}' is not a window of the form ROW,COL,ROWS,COLS: {}", s, e))?;
+    match parts[..] {
+        [row, col, rows, cols] => Ok([row as usize, col as usize, rows as usize, cols as usize]),
+        _ => Err(format!("'{}' is not a window of the form ROW,COL,ROWS,COLS", s)),
+    }
+}
+fn is_window(s: String) -> Result<(), String> {
+    parse_window(&s).map(|_| ())
+}
+fn parse_pad(s: &str) -> Result<[u16; 4], String> {
+    let parts = s.split(',').map(str::parse::<u16>).collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("'{}' is not padding of the form N or TOP,RIGHT,BOTTOM,LEFT: {}", s, e))?;
+    match parts[..] {
+        [n] => Ok([n; 4]),
+        [top, right, bottom, left] => Ok([top, right, bottom, left]),
+        _ => Err(format!("'{}' is not padding of the form N or TOP,RIGHT,BOTTOM,LEFT", s)),
+    }
+}
+fn is_pad(s: String) -> Result<(), String> {
+    parse_pad(&s).map(|_| ())
+}
+fn parse_crop(s: &str) -> Result<[u32; 4], String> {
+    let parts = s.split(',').map(str::parse::<u32>).collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("'{}' is not a region of the form X,Y,W,H: {}", s, e))?;
+    match parts[..] {
+        [_, _, 0, _] | [_, _, _, 0] => Err(format!("'{}' is an empty region", s)),
+        [x, y, w, h] => Ok([x, y, w, h]),
+        _ => Err(format!("'{}' is not a region of the form X,Y,W,H", s)),
+    }
+}
+fn is_crop(s: String) -> Result<(), String> {
+    parse_crop(&s).map(|_| ())
+}
 
-fn dither(img: RgbImage, colors: &[[u8; 3]]) -> Vec<usize> {
-    // The magic number is 3
-    let (width, height) = img.dimensions();
-    let mut res = Vec::with_capacity(width as usize * height as usize);
-    let mut raw = img.into_raw();
-
-    for y in 0..height {
-        for x in 0..width {
-            let cur_idx = 3 * (x + y * width) as usize;
-
-            let (dithered_idx, diff) = {
-                let cur_pixel = &raw[cur_idx..cur_idx + 3];
-                let (dithered_idx, dithered) = colors.iter().enumerate()
-                    .min_by_key(|&(_, col)| {
-                        cur_pixel.into_iter()
-                            .zip(col)
-                            .map(|(a, b)| *a as isize - *b as isize)
-                            .map(|n| n * n)
-                            .fold(0, Add::add)
-                    }).unwrap();
-                let diff = cur_pixel.into_iter()
-                    .zip(dithered)
-                    .map(|(a, b)| *a as i16 - *b as i16)
-                    .collect::<Vec<i16>>();
-
-                (dithered_idx, diff)
+#[derive(Clone, Copy)]
+enum FrameSelect {
+    Largest,
+    Smallest,
+    Index(usize),
+}
+fn parse_frame_select(s: &str) -> Result<FrameSelect, String> {
+    match s {
+        "largest" => Ok(FrameSelect::Largest),
+        "smallest" => Ok(FrameSelect::Smallest),
+        _ => s.parse().map(FrameSelect::Index)
+            .map_err(|_| format!("'{}' is not 'largest', 'smallest', or an index", s)),
+    }
+}
+fn is_frame_select(s: String) -> Result<(), String> {
+    parse_frame_select(&s).map(|_| ())
+}
+
+fn read_u16(data: &[u8], at: usize, big_endian: bool) -> Option<u16> {
+    let bytes = data.get(at..at + 2)?;
+    Some(if big_endian {
+        (bytes[0] as u16) << 8 | bytes[1] as u16
+    } else {
+        (bytes[1] as u16) << 8 | bytes[0] as u16
+    })
+}
+fn read_u32(data: &[u8], at: usize, big_endian: bool) -> Option<u32> {
+    let (a, b) = (read_u16(data, at, big_endian)? as u32, read_u16(data, at + 2, big_endian)? as u32);
+    Some(if big_endian { a << 16 | b } else { b << 16 | a })
+}
+
+fn read_u24(data: &[u8], at: usize) -> Option<u32> {
+    let bytes = data.get(at..at + 3)?;
+    Some((bytes[2] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[0] as u32)
+}
+
+// A sub-image in a container, as its dimensions and whatever it takes to decode only that image
+type ContainerFrame<T> = ((u32, u32), T);
+
+// Each image as a standalone ICO file holding only it
+fn ico_frames(data: &[u8]) -> Option<Vec<ContainerFrame<Vec<u8>>>> {
+    let count = read_u16(data, 4, false)? as usize;
+    (0..count).map(|i| {
+        let entry = data.get(6 + 16 * i..22 + 16 * i)?;
+        // A zero dimension in the directory means 256
+        let dimension = |n: u8| if n == 0 { 256 } else { n as u32 };
+        let size = read_u32(entry, 8, false)? as usize;
+        let offset = read_u32(entry, 12, false)? as usize;
+
+        // Rebuild an ICO with a one-entry directory pointing just past itself
+        let mut file = vec![0, 0, 1, 0, 1, 0];
+        file.extend_from_slice(&entry[..12]);
+        file.extend_from_slice(&[22, 0, 0, 0]);
+        file.extend_from_slice(data.get(offset..offset + size)?);
+        Some(((dimension(entry[0]), dimension(entry[1])), file))
+    }).collect()
+}
+// Each image as the offset of its directory, which only needs the one copy of the file made once
+// one of them has been picked
+fn tiff_frames(data: &[u8]) -> Option<Vec<ContainerFrame<u32>>> {
+    let big_endian = data.starts_with(b"MM");
+    let mut frames = Vec::new();
+    let mut offset = read_u32(data, 4, big_endian)? as usize;
+
+    while offset != 0 && frames.len() < 1024 {
+        let count = read_u16(data, offset, big_endian)? as usize;
+        let mut dimensions = (0, 0);
+        for i in 0..count {
+            let entry = offset + 2 + 12 * i;
+            let value = match read_u16(data, entry + 2, big_endian)? {
+                3 => read_u16(data, entry + 8, big_endian)? as u32,
+                _ => read_u32(data, entry + 8, big_endian)?,
             };
+            match read_u16(data, entry, big_endian)? {
+                256 => dimensions.0 = value,
+                257 => dimensions.1 = value,
+                _ => (),
+            }
+        }
 
-            res.push(dithered_idx);
+        frames.push((dimensions, offset as u32));
 
-            // This only supports dithering algorithms which modify ahead
-            macro_rules! pix_add {
-                ($x:expr, $y:expr, $numerator:expr, $denominator:expr) => {{
-                    if $x > 0 && $x < width {
-                        if $y < height {
-                            let idx = 3 * ($x + $y * width) as usize;
-                            for (channel, offset) in raw[idx..idx + 3].iter_mut().zip(&diff) {
-                                *channel = u8::value_from(*channel as i16 + *offset * $numerator / $denominator).unwrap_or_saturate();
+        offset = read_u32(data, offset + 2 + 12 * count, big_endian)? as usize;
+    }
+    Some(frames)
+}
+fn open_frame(data: &[u8], file: &str, select: FrameSelect) -> Result<DynamicImage, String> {
+    let corrupt = || format!("'{}' has a corrupt image directory", file);
+    if data.starts_with(&[0, 0, 1, 0]) {
+        let frame = pick_frame(ico_frames(data).ok_or_else(corrupt)?, select, file)?;
+        image::load_from_memory_with_format(&frame, ImageFormat::ICO).map_err(|e| e.to_string())
+    } else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+        // Point the header's first directory at the picked image so it's the one that gets decoded
+        let pointer = pick_frame(tiff_frames(data).ok_or_else(corrupt)?, select, file)?;
+        let mut frame = data.to_vec();
+        frame[4..8].copy_from_slice(&if data.starts_with(b"MM") { pointer.to_be_bytes() } else { pointer.to_le_bytes() });
+        image::load_from_memory_with_format(&frame, ImageFormat::TIFF).map_err(|e| e.to_string())
+    } else {
+        // Anything else only holds the one image
+        match select {
+            FrameSelect::Index(n) if n > 0 => Err(format!("frame {} is out of range, '{}' only has 1 image", n, file)),
+            _ => open_data(data, file),
+        }
+    }
+}
+fn pick_frame<T>(mut frames: Vec<ContainerFrame<T>>, select: FrameSelect, file: &str) -> Result<T, String> {
+    let count = frames.len();
+    let area = |&((w, h), _): &ContainerFrame<T>| w as u64 * h as u64;
+    let frame = match select {
+        FrameSelect::Largest => frames.into_iter().max_by_key(area),
+        FrameSelect::Smallest => frames.into_iter().min_by_key(area),
+        FrameSelect::Index(n) if n < count => Some(frames.swap_remove(n)),
+        FrameSelect::Index(n) => return Err(format!("frame {} is out of range, '{}' only has {} images", n, file, count)),
+    };
+    frame.map(|(_, frame)| frame).ok_or_else(|| format!("'{}' doesn't contain any images", file))
+}
 
-                            }
-                        }
+static FILTERS: &[&str] = &["auto", "nearest", "triangle", "gaussian", "catmullrom", "lanczos3"];
+static FORMATS: &[&str] = &["png", "jpeg", "gif", "webp", "tiff", "tga", "bmp", "ico"];
+fn determine_format(format_str: &str) -> ImageFormat {
+    match format_str {
+        "png" => ImageFormat::PNG,
+        "jpeg" => ImageFormat::JPEG,
+        "gif" => ImageFormat::GIF,
+        "webp" => ImageFormat::WEBP,
+        "tiff" => ImageFormat::TIFF,
+        "tga" => ImageFormat::TGA,
+        "bmp" => ImageFormat::BMP,
+        "ico" => ImageFormat::ICO,
+        _ => unreachable!(),
+    }
+}
+static DITHERS: &[&str] = &["none", "floyd-steinberg", "atkinson", "jarvis", "stucki", "sierra", "sierra-lite", "bayer", "bayer8"];
+
+fn determine_dither(dither_str: &str) -> Dither {
+    match dither_str {
+        "none" => Dither::None,
+        "floyd-steinberg" => Dither::FloydSteinberg,
+        "atkinson" => Dither::Atkinson,
+        "jarvis" => Dither::Jarvis,
+        "stucki" => Dither::Stucki,
+        "sierra" => Dither::Sierra,
+        "sierra-lite" => Dither::SierraLite,
+        "bayer" => Dither::Bayer4,
+        "bayer8" => Dither::Bayer8,
+        _ => unreachable!(),
+    }
+}
+fn parse_kernel(s: &str) -> Result<Vec<(i32, i32, i32, i32)>, String> {
+    let entries = s.split(';').map(|entry| {
+        let parts = entry.split(',').map(|n| n.trim().parse::<i32>()).collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("'{}' is not of the form dx,dy,weight: {}", entry, e))?;
+        match parts[..] {
+            [dx, dy, weight] => {
+                // Error can only be pushed to pixels which haven't been visited yet
+                if dy < 0 || (dy == 0 && dx <= 0) {
+                    Err(format!("'{}' is not ahead of the current pixel", entry))
+                } else if weight <= 0 {
+                    Err(format!("'{}' does not have a positive weight", entry))
+                } else {
+                    Ok((dx, dy, weight))
+                }
+            },
+            _ => Err(format!("'{}' is not of the form dx,dy,weight", entry)),
+        }
+    }).collect::<Result<Vec<_>, _>>()?;
+
+    // The error is split between the entries in proportion to their weights
+    let total = entries.iter().map(|&(_, _, weight)| weight).sum::<i32>();
+    Ok(entries.into_iter().map(|(dx, dy, weight)| (dx, dy, weight, total)).collect())
+}
+fn is_kernel(s: String) -> Result<(), String> {
+    parse_kernel(&s).map(|_| ())
+}
+
+fn is_cube_bits(s: String) -> Result<(), String> {
+    match s.parse::<u8>() {
+        Ok(1..=5) => Ok(()),
+        Ok(_) => Err(String::from("the cube must have between 1 and 5 bits per channel")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn scroll_lines(lines: &[Vec<ANSIString>], cols: usize, delay: Duration) {
+    let width = lines.first().map_or(0, Vec::len);
+    let cols = cols.min(width);
+
+    for offset in 0..width - cols + 1 {
+        if offset > 0 && !lines.is_empty() {
+            // Move back up to overwrite the previous window in place
+            print!("\x1b[{}A", lines.len());
+        }
+        for line in lines {
+            println!("{}", ANSIStrings(&line[offset..offset + cols]));
+        }
+        thread::sleep(delay);
+    }
+}
+
+// Every frame of a GIF composed onto its canvas, along with how long to show it for. Anything
+// other than a GIF gives `None`.
+fn gif_frames(data: &[u8], file: &str) -> Result<Option<Vec<(RgbaImage, Duration)>>, String> {
+    if !data.starts_with(b"GIF8") {
+        return Ok(None);
+    }
+
+    let describe = |e: gif::DecodingError| match e {
+        gif::DecodingError::Format(e) | gif::DecodingError::Internal(e) => format!("cannot decode '{}': {}", file, e),
+        gif::DecodingError::Io(e) => format!("cannot decode '{}': {}", file, error_message(e)),
+    };
+    let mut decoder = gif::Decoder::new(data);
+    decoder.set(gif::ColorOutput::RGBA);
+    let mut reader = decoder.read_info().map_err(describe)?;
+
+    let (width, height) = (reader.width() as u32, reader.height() as u32);
+    let mut canvas = RgbaImage::new(width, height);
+    let mut frames = Vec::new();
+    let mut failed = None;
+    loop {
+        let frame = match reader.read_next_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            // A frame that can't be decoded is left out, and the decoder carries on past it. Failing
+            // again straight after means it can't get any further, like when the file is cut short.
+            Err(e) => {
+                if failed.is_some() {
+                    break;
+                }
+                let message = describe(e);
+                eprintln!("pic2term: warning: skipping a frame: {}", message);
+                failed = Some(message);
+                continue;
+            },
+        };
+        failed = None;
+        let previous = canvas.clone();
+        let (left, top, frame_w) = (frame.left as u32, frame.top as u32, frame.width as u32);
+        for (i, pixel) in frame.buffer.chunks(4).enumerate() {
+            let (x, y) = (left + i as u32 % frame_w, top + i as u32 / frame_w);
+            // Transparent pixels leave whatever the earlier frames drew showing through
+            if pixel[3] != 0 && x < width && y < height {
+                canvas.put_pixel(x, y, Rgba { data: [pixel[0], pixel[1], pixel[2], pixel[3]] });
+            }
+        }
+        // Browsers show frames with no delay (or next to none) for a tenth of a second, so do the same
+        let delay = if frame.delay < 2 { 10 } else { frame.delay };
+        frames.push((canvas.clone(), Duration::from_millis(delay as u64 * 10)));
+
+        match frame.dispose {
+            DisposalMethod::Background => {
+                for y in top..(top + frame.height as u32).min(height) {
+                    for x in left..(left + frame_w).min(width) {
+                        canvas.put_pixel(x, y, Rgba { data: [0, 0, 0, 0] });
                     }
-                }};
-            };
+                }
+            },
+            DisposalMethod::Previous => canvas = previous,
+            DisposalMethod::Any | DisposalMethod::Keep => {},
+        }
+    }
 
-            pix_add!(x + 1, y, 7, 48);
-            pix_add!(x + 2, y, 5, 48);
-            pix_add!(x - 2, y + 1, 3, 48);
-            pix_add!(x - 1, y + 1, 5, 48);
-            pix_add!(x, y + 1, 7, 48);
-            pix_add!(x + 1, y + 1, 5, 48);
-            pix_add!(x + 2, y + 1, 3, 48);
-            pix_add!(x - 2, y + 2, 1, 48);
-            pix_add!(x - 1, y + 2, 3, 48);
-            pix_add!(x, y + 2, 5, 48);
-            pix_add!(x + 1, y + 2, 3, 48);
-            pix_add!(x + 2, y + 2, 1, 48);
+    match failed {
+        Some(message) if frames.is_empty() => Err(message),
+        _ => Ok(Some(frames)),
+    }
+}
+// Every frame of an animated WebP composed onto its canvas, along with how long to show it for.
+// Anything else gives `None`. Frames are decoded the same way as still WebPs, which means lossy
+// ones only, and without their alpha.
+fn webp_frames(data: &[u8], file: &str) -> Result<Option<Vec<(RgbaImage, Duration)>>, String> {
+    // Animations always use the extended format, with the animation flag set in its header
+    if !data.starts_with(b"RIFF") || data.get(8..16) != Some(b"WEBPVP8X") || data.get(20).is_none_or(|flags| flags & 0x02 == 0) {
+        return Ok(None);
+    }
+
+    let corrupt = || format!("'{}' has a corrupt animation", file);
+    let (width, height) = (read_u24(data, 24).ok_or_else(corrupt)? + 1, read_u24(data, 27).ok_or_else(corrupt)? + 1);
+    let mut canvas = RgbaImage::new(width, height);
+    let mut frames = Vec::new();
+    let mut offset = 12;
+    while let (Some(kind), Some(size)) = (data.get(offset..offset + 4), read_u32(data, offset + 4, false)) {
+        let chunk = data.get(offset + 8..offset + 8 + size as usize).ok_or_else(corrupt)?;
+        // Chunks are padded out to an even length
+        offset += 8 + size as usize + size as usize % 2;
+        if kind != b"ANMF" {
+            continue;
+        }
+
+        let position = (read_u24(chunk, 0).ok_or_else(corrupt)? * 2, read_u24(chunk, 3).ok_or_else(corrupt)? * 2);
+        let delay = read_u24(chunk, 12).ok_or_else(corrupt)?;
+        let dispose = chunk.get(15).ok_or_else(corrupt)? & 0x01 != 0;
+
+        // The frame's bitstream comes after any alpha, and gets rebuilt into a still WebP of its own
+        let mut at = 16;
+        let bitstream = loop {
+            let (kind, size) = (chunk.get(at..at + 4).ok_or_else(corrupt)?, read_u32(chunk, at + 4, false).ok_or_else(corrupt)? as usize);
+            match kind {
+                b"VP8 " => break chunk.get(at..at + 8 + size).ok_or_else(corrupt)?,
+                b"VP8L" => return Err(format!("cannot decode '{}': lossless WebP frames are not supported", file)),
+                _ => at += 8 + size + size % 2,
+            }
+        };
+        let mut still = b"RIFF".to_vec();
+        still.extend_from_slice(&(4 + bitstream.len() as u32).to_le_bytes());
+        still.extend_from_slice(b"WEBP");
+        still.extend_from_slice(bitstream);
+        let frame = image::load_from_memory_with_format(&still, ImageFormat::WEBP)
+            .map_err(|e| format!("cannot decode '{}': {}", file, error_message(e)))?.to_rgba();
+
+        if !canvas.copy_from(&frame, position.0, position.1) {
+            return Err(corrupt());
+        }
+        // Browsers show frames with next to no delay for a tenth of a second, as with GIFs
+        frames.push((canvas.clone(), Duration::from_millis(if delay < 20 { 100 } else { delay as u64 })));
+        if dispose {
+            for y in position.1..position.1 + frame.height() {
+                for x in position.0..position.0 + frame.width() {
+                    canvas.put_pixel(x, y, Rgba { data: [0, 0, 0, 0] });
+                }
+            }
         }
     }
 
-    res
+    Ok(Some(frames))
 }
+// The frames of whichever kind of animation this is, or `None` if it's not one
+fn animation_frames(data: &[u8], file: &str) -> Result<Option<Vec<(RgbaImage, Duration)>>, String> {
+    match gif_frames(data, file)? {
+        Some(frames) => Ok(Some(frames)),
+        None => webp_frames(data, file),
+    }
+}
+// Drops any frame that would come sooner after the last one shown than `fps` allows, showing that
+// one for longer instead so the animation still takes as long
+fn cap_fps(frames: Vec<(RgbaImage, Duration)>, fps: f64) -> Vec<(RgbaImage, Duration)> {
+    let interval = Duration::from_secs_f64(1.0 / fps);
+    let mut capped: Vec<(RgbaImage, Duration)> = Vec::with_capacity(frames.len());
+    for (frame, delay) in frames {
+        match capped.last_mut() {
+            Some(&mut (_, ref mut shown)) if *shown < interval => *shown += delay,
+            _ => capped.push((frame, delay)),
+        }
+    }
+    capped
+}
+// What the environment says the terminal supports, as one of the --colors values or "truecolor"
+fn detect_colors() -> &'static str {
+    let colorterm = env::var("COLORTERM").unwrap_or_default();
+    if colorterm == "truecolor" || colorterm == "24bit" {
+        return "truecolor";
+    }
+    let term = env::var("TERM").unwrap_or_default();
+    if term.ends_with("-88color") {
+        "88"
+    } else if term.ends_with("-16color") || term.ends_with("-8color")
+        || ["ansi", "cons25", "dumb", "linux", "vt100", "vt220"].contains(&term.as_str()) {
+        "16"
+    } else {
+        // Which is what everything was rendered for before there was any detection
+        "256"
+    }
+}
+// The orientation a photo's EXIF data says to show it in, if it has any
+fn exif_orientation(data: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new().read_from_container(&mut Cursor::new(data)).ok()?;
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?.value.get_uint(0)
+}
+// Turns and mirrors an image the way an EXIF orientation says to
+fn orient(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+fn open_image(file: &str) -> Result<DynamicImage, String> {
+    read_source(file).and_then(|data| open_data(&data, file))
+}
+// Decodes an image read by read_source, local files still going by their extension the way image::open does,
+// and anything without one it knows going by its contents
+fn open_data(data: &[u8], file: &str) -> Result<DynamicImage, String> {
+    if let Some(deep) = open_deep_png(data, false) {
+        return deep.map_err(|e| format!("cannot decode '{}': {}", file, e));
+    }
+    let extension = Path::new(file).extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase);
+    let format = match extension.as_deref() {
+        _ if is_url(file) => None,
+        Some("jpg") => Some("jpeg"),
+        Some("tif") => Some("tiff"),
+        Some(extension) => FORMATS.iter().cloned().find(|&format| format == extension),
+        None => None,
+    };
+    match format {
+        Some(format) => image::load_from_memory_with_format(data, determine_format(format)),
+        None => image::load_from_memory(data),
+    }.map_err(|e| format!("cannot decode '{}': {}", file, error_message(e)))
+}
+// Decodes an image as the format it was said to be in, whatever its extension or first few bytes suggest
+fn open_as(data: &[u8], file: &str, format_str: &str) -> Result<DynamicImage, String> {
+    let format = determine_format(format_str);
+    if let Some(deep) = open_deep_png(data, false).filter(|_| format == ImageFormat::PNG) {
+        return deep.map_err(|e| format!("cannot decode '{}' as {}: {}", file, format_str, e));
+    }
+    image::load_from_memory_with_format(data, format)
+        .map_err(|e| format!("cannot decode '{}' as {}: {}", file, format_str, error_message(e)))
+}
+// image keeps only the high byte of each sample in a 16 bit PNG, which bands smooth gradients, so
+// these get decoded here instead and rounded to 8 bits rather than cut off. Samples that have fewer
+// bits in them than 16, as the file's sBIT chunk says, are scaled from however many they have, and
+// `stretch` spreads the range of values the image actually uses over all 8 bits instead.
+fn open_deep_png(data: &[u8], stretch: bool) -> Option<Result<DynamicImage, String>> {
+    let mut decoder = png::Decoder::new(data);
+    png::HasParameters::set(&mut decoder, png::TRANSFORM_IDENTITY);
+    let (info, mut reader) = decoder.read_info().ok()?;
+    // png can't put interlaced 16 bit images back together, so those are left to image
+    if info.bit_depth != png::BitDepth::Sixteen || reader.info().interlaced {
+        return None;
+    }
+    let mut buf = vec![0; info.buffer_size()];
+    if let Err(e) = reader.next_frame(&mut buf) {
+        return Some(Err(e.to_string()));
+    }
+    let samples: Vec<u16> = buf.chunks(2).map(|b| (b[0] as u16) << 8 | b[1] as u16).collect();
+    let channels = info.color_type.samples();
+    let alpha = matches!(info.color_type, png::ColorType::GrayscaleAlpha | png::ColorType::RGBA);
+    let is_color = |i: usize| !alpha || i % channels != channels - 1;
 
-fn main() {
-    let matches = App::new("pic2term")
+    let significant = significant_bits(data).unwrap_or_default();
+    let bits = |i: usize| significant.get(i % channels).map_or(16, |&n| n.clamp(1, 16) as u32);
+    // The values are meant to be shifted up to fill all 16 bits, but plenty of files leave them in the
+    // low bits, which is all that would explain values that fit there and use their lowest bits
+    let shifted = samples.iter().enumerate().any(|(i, &v)| v as u32 >> bits(i) != 0) ||
+        samples.iter().enumerate().all(|(i, &v)| v as u32 & ((1 << (16 - bits(i))) - 1) == 0);
+    let levels = if stretch {
+        let values = samples.iter().enumerate().filter(|&(i, _)| is_color(i)).map(|(_, &v)| v);
+        let (lo, hi) = values.fold((u16::MAX, 0), |(lo, hi), v| (lo.min(v), hi.max(v)));
+        trace!("16 bit samples: {}..{}", lo, hi);
+        Some((lo as u32, (hi as u32).saturating_sub(lo as u32).max(1)))
+    } else {
+        None
+    };
+    let bytes = samples.iter().enumerate().map(|(i, &v)| {
+        let (v, span) = match levels {
+            Some((lo, span)) if is_color(i) => (v as u32 - lo, span),
+            _ if shifted => (v as u32 >> (16 - bits(i)), (1 << bits(i)) - 1),
+            _ => (v as u32, (1 << bits(i)) - 1),
+        };
+        ((v * 255 + span / 2) / span).min(255) as u8
+    }).collect();
+    let (w, h) = (info.width, info.height);
+    let image = match info.color_type {
+        png::ColorType::Grayscale => ImageBuffer::from_raw(w, h, bytes).map(DynamicImage::ImageLuma8),
+        png::ColorType::GrayscaleAlpha => ImageBuffer::from_raw(w, h, bytes).map(DynamicImage::ImageLumaA8),
+        png::ColorType::RGB => ImageBuffer::from_raw(w, h, bytes).map(DynamicImage::ImageRgb8),
+        png::ColorType::RGBA => ImageBuffer::from_raw(w, h, bytes).map(DynamicImage::ImageRgba8),
+        png::ColorType::Indexed => None,
+    };
+    Some(image.ok_or_else(|| "image data is truncated".to_string()))
+}
+// The bits each channel of a PNG had before being stored, from its sBIT chunk
+fn significant_bits(data: &[u8]) -> Option<Vec<u8>> {
+    let mut at = 8;
+    loop {
+        let length = read_u32(data, at, true)? as usize;
+        match data.get(at + 4..at + 8)? {
+            b"sBIT" => return data.get(at + 8..at + 8 + length).map(<[u8]>::to_vec),
+            // It has to come before the image data
+            b"IDAT" => return None,
+            _ => at += 12 + length,
+        }
+    }
+}
+// The whole of a file, or of whatever's at the other end of a URL
+fn read_source(file: &str) -> Result<Vec<u8>, String> {
+    if is_url(file) {
+        return fetch(file);
+    }
+    let mut data = Vec::new();
+    File::open(file).and_then(|mut f| f.read_to_end(&mut data))
+        .map_err(|e| format!("cannot open '{}': {}", file, error_message(e)))?;
+    Ok(data)
+}
+fn is_url(file: &str) -> bool {
+    file.starts_with("http://") || file.starts_with("https://")
+}
+#[cfg(feature = "url")]
+fn fetch(url: &str) -> Result<Vec<u8>, String> {
+    let response = ureq::get(url).timeout(Duration::from_secs(30)).call().map_err(|e| match e {
+        ureq::Error::Status(code, response) => format!("cannot fetch '{}': the server responded with {} {}", url, code, response.status_text()),
+        // ureq's own message leads with the URL, which ours already names
+        ureq::Error::Transport(e) => {
+            let message = e.to_string();
+            format!("cannot fetch '{}': {}", url, error_message(message.trim_start_matches(&format!("{}: ", url))))
+        },
+    })?;
+    let mut data = Vec::new();
+    response.into_reader().read_to_end(&mut data)
+        .map_err(|e| format!("cannot fetch '{}': {}", url, error_message(e)))?;
+    Ok(data)
+}
+#[cfg(not(feature = "url"))]
+fn fetch(url: &str) -> Result<Vec<u8>, String> {
+    Err(format!("cannot fetch '{}': pic2term was built without the url feature", url))
+}
+// The error's description, without the "(os error N)" suffix io errors carry
+fn error_message<E: fmt::Display>(e: E) -> String {
+    let message = e.to_string();
+    match message.rfind(" (os error ") {
+        Some(end) if message.ends_with(')') => message[..end].to_string(),
+        _ => message,
+    }
+}
+// Defaults to use instead of the built-in ones, named after the flags that still override them
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    filter: Option<String>,
+    dither: Option<String>,
+    colors: Option<u16>,
+    cell_aspect: Option<f32>,
+}
+impl Config {
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "filter" => self.filter.clone(),
+            "dither" => self.dither.clone(),
+            "colors" => self.colors.map(|colors| colors.to_string()),
+            "cell-aspect" => self.cell_aspect.map(|ratio| ratio.to_string()),
+            _ => None,
+        }
+    }
+}
+fn default_config_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()).map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(dir.join("pic2term").join("config.toml"))
+}
+// Loads the config file at `path`, or the default one if there is one
+fn load_config(path: Option<&str>) -> Result<Config, String> {
+    let (path, required) = match path.map(PathBuf::from) {
+        Some(path) => (path, true),
+        None => match default_config_path() {
+            Some(path) => (path, false),
+            None => return Ok(Config::default()),
+        },
+    };
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(ref e) if !required && e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(format!("cannot open config '{}': {}", path.display(), error_message(e))),
+    };
+    let invalid = |e: String| format!("cannot load config '{}': {}", path.display(), e);
+    let config = toml::from_str::<Config>(&text).map_err(|e| invalid(e.message().to_string()))?;
+    let pick = |name: &str, value: &Option<String>, values: &[&str]| match *value {
+        Some(ref value) if !values.contains(&value.as_str()) => Err(format!("'{}' isn't a valid {}, pick one of {}", value, name, values.join(", "))),
+        _ => Ok(()),
+    };
+    pick("filter", &config.filter, FILTERS).map_err(invalid)?;
+    pick("dither", &config.dither, DITHERS).map_err(invalid)?;
+    match config.colors {
+        Some(256) | Some(88) | Some(16) | None => {},
+        Some(colors) => return Err(invalid(format!("terminals have 256, 88 or 16 colors, not {}", colors))),
+    }
+    if let Some(ratio) = config.cell_aspect {
+        is_cell_aspect(ratio.to_string()).map_err(invalid)?;
+    }
+    Ok(config)
+}
+// Exits unsuccessfully after explaining why on stderr
+fn fail(message: &str) -> ! {
+    eprintln!("pic2term: {}", message);
+    process::exit(1);
+}
+fn play_frames(frames: &[(Vec<Vec<ANSIString>>, Duration)], forever: bool) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    // Every frame is drawn over the last one from the top left, so start out with a clear screen
+    write!(stdout, "\x1b[2J")?;
+    loop {
+        for &(ref lines, delay) in frames {
+            write!(stdout, "\x1b[H")?;
+            write_lines(&mut stdout, lines)?;
+            thread::sleep(delay);
+        }
+        if !forever {
+            return Ok(());
+        }
+    }
+}
+// Asks the terminal for its background color with OSC 11, giving up if it takes longer than a
+// moment to answer. The answer arrives as input, so the terminal has to stop echoing and buffering
+// lines for as long as it takes to read it.
+#[cfg(unix)]
+fn query_background() -> Option<[u8; 3]> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty").ok()?;
+    let fd = tty.as_raw_fd();
+    let mut original = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut original) } != 0 {
+        return None;
+    }
+    let mut raw = original;
+    raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+        return None;
+    }
+    let reply = read_background_reply(&mut tty, fd);
+    // Whatever's left of a reply that was cut short shouldn't end up at the shell's prompt
+    unsafe {
+        libc::tcflush(fd, libc::TCIFLUSH);
+        libc::tcsetattr(fd, libc::TCSANOW, &original);
+    }
+    parse_osc_color(&reply?)
+}
+#[cfg(unix)]
+fn read_background_reply(tty: &mut File, fd: libc::c_int) -> Option<String> {
+    write!(tty, "\x1b]11;?\x07").and_then(|_| tty.flush()).ok()?;
+    let deadline = Instant::now() + Duration::from_millis(200);
+    let mut reply = Vec::new();
+    // Terminals end the reply the same way the query was, with either BEL or ST
+    while !reply.ends_with(b"\x07") && !reply.ends_with(b"\x1b\\") {
+        let left = deadline.checked_duration_since(Instant::now())?;
+        let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut pollfd, 1, left.as_millis() as libc::c_int) } <= 0 {
+            return None;
+        }
+        let mut byte = [0];
+        if tty.read(&mut byte).ok()? == 0 {
+            return None;
+        }
+        reply.push(byte[0]);
+    }
+    String::from_utf8(reply).ok()
+}
+#[cfg(not(unix))]
+fn query_background() -> Option<[u8; 3]> {
+    None
+}
+// The color in an OSC 11 reply such as `rgb:RRRR/GGGG/BBBB`, whose channels can each have
+// anywhere from one to four hex digits
+fn parse_osc_color(reply: &str) -> Option<[u8; 3]> {
+    let start = reply.find("rgb:")? + 4;
+    let end = reply[start..].find(['\x07', '\x1b']).map_or(reply.len(), |end| start + end);
+    let channels = reply[start..end].split('/').map(|hex| {
+        if hex.is_empty() || hex.len() > 4 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let max = (1 << (4 * hex.len())) - 1;
+        u32::from_str_radix(hex, 16).ok().map(|n| ((n * 255 + max / 2) / max) as u8)
+    }).collect::<Option<Vec<_>>>()?;
+    match channels[..] {
+        [r, g, b] => Some([r, g, b]),
+        _ => None,
+    }
+}
+// Set whenever the terminal changes size, once watching for it has started
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn watch_resizes() -> Result<(), String> {
+    extern "C" fn on_resize(_: libc::c_int) {
+        RESIZED.store(true, Ordering::SeqCst);
+    }
+    if unsafe { libc::signal(libc::SIGWINCH, on_resize as extern "C" fn(libc::c_int) as libc::sighandler_t) } == libc::SIG_ERR {
+        return Err(format!("cannot watch for the terminal being resized: {}", error_message(io::Error::last_os_error())));
+    }
+    Ok(())
+}
+#[cfg(not(unix))]
+fn watch_resizes() -> Result<(), String> {
+    Err(String::from("watching for the terminal being resized is only supported on Unix"))
+}
+// Being interrupted part way through a row leaves the terminal painting whatever comes next in that
+// row's colors, so Ctrl-C puts them back (and the cursor, in case it was hidden) before exiting
+#[cfg(unix)]
+fn reset_on_interrupt() {
+    extern "C" fn on_interrupt(_: libc::c_int) {
+        let reset = b"\x1b[0m\x1b[?25h";
+        unsafe {
+            libc::write(libc::STDOUT_FILENO, reset.as_ptr() as *const libc::c_void, reset.len());
+            libc::_exit(130);
+        }
+    }
+    unsafe {
+        libc::signal(libc::SIGINT, on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+#[cfg(not(unix))]
+fn reset_on_interrupt() {}
+// When each file was last changed, along with its size in case that's all that changed
+fn modified(files: &[&str]) -> Vec<Option<(SystemTime, u64)>> {
+    files.iter()
+        .map(|file| fs::metadata(file).ok().and_then(|metadata| Some((metadata.modified().ok()?, metadata.len()))))
+        .collect()
+}
+// Waits for the terminal to be resized or one of the files to change from what `stamps` says.
+// Dragging a window's edge resizes it over and over and programs can write files out a piece at a
+// time, so this waits for things to stay the same for a moment too.
+fn wait_for_change(files: &[&str], mut stamps: Vec<Option<(SystemTime, u64)>>) {
+    loop {
+        let now = modified(files);
+        if RESIZED.swap(false, Ordering::SeqCst) || now != stamps {
+            stamps = now;
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    loop {
+        thread::sleep(Duration::from_millis(200));
+        let now = modified(files);
+        if !RESIZED.swap(false, Ordering::SeqCst) && now == stamps {
+            break;
+        }
+        stamps = now;
+    }
+}
+fn copy_to_clipboard(text: &str) -> io::Result<()> {
+    // OSC 52 asks the terminal itself to set the clipboard, so this works over SSH too. It goes
+    // straight to the controlling terminal in case stdout is redirected.
+    let mut tty = OpenOptions::new().write(true).open("/dev/tty")?;
+    write!(tty, "\x1b]52;c;{}\x07", STANDARD.encode(text))?;
+    tty.flush()
+}
+
+fn set_background(file: &str) -> Result<(), String> {
+    if env::var("TERM_PROGRAM").ok().as_deref() != Some("iTerm.app") {
+        return Err(String::from("only iTerm2 supports setting a background image"));
+    }
+    // iTerm2 loads the image itself, so it needs a path it can find regardless of our directory
+    let path = fs::canonicalize(file).map_err(|e| format!("cannot open '{}': {}", file, error_message(e)))?;
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    write!(stdout, "\x1b]1337;SetBackgroundImageFile={}\x07", STANDARD.encode(path.to_string_lossy().as_bytes()))
+        .and_then(|_| stdout.flush())
+        .map_err(error_message)
+}
+
+// Draws a prepared image at a size with a terminal graphics protocol
+type Graphics = fn(RgbImage, u16, u16, &RenderOptions) -> String;
+
+fn app() -> App<'static, 'static> {
+    App::new("pic2term")
         .version("0.1.0")
         .author("Lyn Levenick <lyn.levenick@gmail.com>")
         .about("Renders images to the terminal with Unicode characters")
+        .after_help("Animated GIFs and WebPs are played back frame by frame. Animated WebPs have to be lossy, and their frames are \
+                     drawn opaque, covering whatever is under them without any transparency or blending.")
         .arg(Arg::with_name("width")
              .long("width")
-             .help("The width (in columns) to resize the image to")
+             .help("The width (in columns, or as a percentage of the terminal) to resize the image to")
              .value_name("WIDTH")
-             .validator(is_u16))
+             .validator(is_dimension))
         .arg(Arg::with_name("height")
              .long("height")
-             .help("The height (in rows) to resize the image to")
+             .help("The height (in rows, or as a percentage of the terminal) to resize the image to")
              .value_name("HEIGHT")
+             .validator(is_dimension))
+        .arg(Arg::with_name("scale")
+             .long("scale")
+             .help("Multiplies the size the image would otherwise be rendered at, e.g. 0.5 for half size")
+             .value_name("FACTOR")
+             .validator(is_scale))
+        .arg(Arg::with_name("fit")
+             .long("fit")
+             .help("Fits the image inside --width and --height without stretching it, padding the rest with --background")
+             .requires_all(&["width", "height"]))
+        .arg(Arg::with_name("fill")
+             .long("fill")
+             .help("Covers all of --width and --height without stretching the image, cropping off whatever overflows")
+             .requires_all(&["width", "height"])
+             .conflicts_with_all(&["fit", "montage"]))
+        .arg(Arg::with_name("max-width")
+             .long("max-width")
+             .help("The most columns to ever render at, shrinking anything larger to fit")
+             .default_value("1000")
+             .value_name("COLS")
+             .validator(is_positive))
+        .arg(Arg::with_name("max-height")
+             .long("max-height")
+             .help("The most rows to ever render at, shrinking anything larger to fit")
+             .default_value("1000")
+             .value_name("ROWS")
+             .validator(is_positive))
+        .arg(Arg::with_name("reserve-rows")
+             .long("reserve-rows")
+             .help("The number of terminal rows to leave free when fitting the image, e.g. for a prompt")
+             .default_value("0")
+             .value_name("ROWS")
              .validator(is_u16))
+        .arg(Arg::with_name("cell-aspect")
+             .long("cell-aspect")
+             .help("How many times taller than they're wide the terminal's cells are, to correct squashed or stretched output")
+             .default_value("2")
+             .value_name("RATIO")
+             .validator(is_cell_aspect))
         .arg(Arg::with_name("filter")
              .long("filter")
-             .help("The filter to use when downscaling the image")
-             .possible_values(&["nearest", "triangle", "gaussian", "catmullrom", "lanczos3"])
+             .help("The filter to use when downscaling the image, auto picks one from how far it is scaled")
+             .possible_values(FILTERS)
              .default_value("nearest")
              .value_name("FILTER"))
+        .arg(Arg::with_name("crop")
+             .long("crop")
+             .help("Only renders the given region of the image, in pixels")
+             .value_name("X,Y,W,H")
+             .validator(is_crop))
+        .arg(Arg::with_name("autocrop-smart")
+             .long("autocrop-smart")
+             .help("Crops to the bounding box of the image's detailed content before sizing"))
+        .arg(Arg::with_name("dejpeg")
+             .long("dejpeg")
+             .help("Smooths the chroma before resizing to reduce JPEG color bleeding"))
+        .arg(Arg::with_name("equalize")
+             .long("equalize")
+             .help("Applies histogram equalization to the luminance before dithering"))
+        .arg(Arg::with_name("stretch-levels")
+             .long("stretch-levels")
+             .help("Spreads the range of values a 16 bit PNG actually uses over all 8 bits it gets shown with"))
+        .arg(Arg::with_name("lut")
+             .long("lut")
+             .help("A .cube 3D LUT to color grade the image with before dithering")
+             .value_name("FILE"))
+        .arg(Arg::with_name("grayscale")
+             .long("grayscale")
+             .help("Renders in shades of gray, using only the palette's neutral colors"))
+        .arg(Arg::with_name("luma")
+             .long("luma")
+             .help("The weights to take brightness with for grayscale, ASCII, Braille, equalizing and smart cropping")
+             .possible_values(&["rec601", "rec709", "average"])
+             .default_value("rec709")
+             .value_name("WEIGHTS"))
+        .arg(Arg::with_name("invert")
+             .long("invert")
+             .help("Inverts the image's colors before dithering, for images made for the opposite background"))
+        .arg(Arg::with_name("rotate")
+             .long("rotate")
+             .help("Turns the image clockwise by this many degrees before it's sized")
+             .possible_values(&["90", "180", "270"])
+             .value_name("DEGREES"))
+        .arg(Arg::with_name("no-autorotate")
+             .long("no-autorotate")
+             .help("Ignores the orientation in a photo's EXIF data, showing its pixels the way they're stored"))
+        .arg(Arg::with_name("flip-h")
+             .long("flip-h")
+             .help("Mirrors the image left to right"))
+        .arg(Arg::with_name("flip-v")
+             .long("flip-v")
+             .help("Mirrors the image top to bottom"))
+        .arg(Arg::with_name("brightness")
+             .long("brightness")
+             .help("An amount to add to every channel before dithering, pass negative ones as --brightness=-N")
+             .default_value("0")
+             .value_name("N")
+             .validator(is_brightness))
+        .arg(Arg::with_name("contrast")
+             .long("contrast")
+             .help("A factor to scale every channel's distance from the midpoint by before dithering")
+             .default_value("1")
+             .value_name("FACTOR")
+             .validator(is_contrast))
+        .arg(Arg::with_name("negative")
+             .long("negative")
+             .help("Swaps every dithered color for the palette color nearest its inverse"))
+        .arg(Arg::with_name("dither")
+             .long("dither")
+             .help("The algorithm to dither with, none picks the nearest color for each pixel and bayer uses a 4x4 matrix")
+             .possible_values(DITHERS)
+             .default_value("jarvis")
+             .value_name("ALGORITHM"))
+        .arg(Arg::with_name("strength")
+             .long("strength")
+             .help("How much of each pixel's error to diffuse, from 0 for the nearest colors to 1 for all of it")
+             .default_value("1")
+             .value_name("FLOAT")
+             .validator(is_strength))
+        .arg(Arg::with_name("serpentine")
+             .long("serpentine")
+             .help("Diffuses error along every other row right to left, which keeps gradients from streaking diagonally"))
+        .arg(Arg::with_name("gamma")
+             .long("gamma")
+             .help("The gamma to linearize colors with before matching them, 1 matches the raw values")
+             .default_value("2.2")
+             .value_name("GAMMA")
+             .validator(is_gamma))
+        .arg(Arg::with_name("colorspace")
+             .long("colorspace")
+             .help("The color space to measure distances between colors in when matching them")
+             .possible_values(&["rgb", "lab"])
+             .default_value("rgb")
+             .value_name("SPACE"))
+        .arg(Arg::with_name("kernel")
+             .long("kernel")
+             .help("A custom error diffusion kernel, as semicolon separated dx,dy,weight entries")
+             .conflicts_with("dither")
+             .value_name("KERNEL")
+             .validator(is_kernel))
+        .arg(Arg::with_name("no-dither")
+             .long("no-dither")
+             .help("Maps each pixel straight to its nearest color, skipping all the work of dithering")
+             .conflicts_with_all(&["dither", "kernel"]))
+        .arg(Arg::with_name("threads")
+             .long("threads")
+             .help("The number of threads to match colors with when there's no error to diffuse, with --no-dither or Bayer dithering")
+             .default_value("1")
+             .value_name("N")
+             .validator(is_threads))
+        .arg(Arg::with_name("downscale-steps")
+             .long("downscale-steps")
+             .help("The number of times to halve the image before the final resize")
+             .default_value("0")
+             .value_name("N")
+             .validator(is_u16))
+        .arg(Arg::with_name("colors")
+             .long("colors")
+             .help("The number of colors the terminal supports, worked out from $COLORTERM and $TERM by default")
+             .possible_values(&["256", "88", "16"])
+             .value_name("COLORS"))
+        .arg(Arg::with_name("palette")
+             .long("palette")
+             .help("A file of the terminal's actual colors to match against, one RRGGBB per line from index 0, with # comments")
+             .conflicts_with_all(&["colors", "cube-bits", "truecolor"])
+             .value_name("FILE"))
+        .arg(Arg::with_name("truecolor")
+             .long("truecolor")
+             .help("Outputs 24-bit color straight from the image instead of dithering to a palette")
+             .conflicts_with_all(&["cube-bits", "dither", "kernel", "preserve-palette"]))
+        .arg(Arg::with_name("cube-bits")
+             .long("cube-bits")
+             .help("Quantizes against a generated RGB cube with this many bits per channel and outputs truecolor")
+             .value_name("BITS")
+             .validator(is_cube_bits))
+        .arg(Arg::with_name("preserve-palette")
+             .long("preserve-palette")
+             .help("Resizes with nearest sampling and skips dithering, for inputs already using the palette's colors")
+             .conflicts_with_all(&["dejpeg", "dither", "equalize", "kernel"]))
+        .arg(Arg::with_name("quadrants")
+             .long("quadrants")
+             .help("Draws with quadrant blocks, for twice the horizontal resolution but only two colors per cell"))
+        .arg(Arg::with_name("braille")
+             .long("braille")
+             .help("Draws with Braille dots, for four times the resolution of half blocks but only in monochrome")
+             .conflicts_with("quadrants"))
+        .arg(Arg::with_name("threshold")
+             .long("threshold")
+             .help("How bright a pixel has to be to get a Braille dot, from 0 to 255")
+             .default_value("128")
+             .value_name("N")
+             .validator(is_threshold))
+        .arg(Arg::with_name("ascii")
+             .long("ascii")
+             .help("Draws with plain characters picked by brightness, without any color or escape codes, by default when NO_COLOR is set")
+             .conflicts_with_all(&["braille", "quadrants"]))
+        .arg(Arg::with_name("blocks")
+             .long("blocks")
+             .help("Draws with half blocks, for twice the vertical resolution, or full blocks, for one color per cell")
+             .possible_values(&["half", "full"])
+             .conflicts_with_all(&["ascii", "braille", "iterm", "kitty", "quadrants", "sixel"])
+             .value_name("half|full"))
+        .arg(Arg::with_name("blend")
+             .long("blend")
+             .help("Draws each cell as one full block in the average of the two pixels half blocks would show")
+             .conflicts_with_all(&["ascii", "blocks", "braille", "hex", "iterm", "kitty", "quadrants", "raw-indices", "sixel"]))
+        .arg(Arg::with_name("hex")
+             .long("hex")
+             .help("Prints the hex color picked for every pixel instead of drawing them, for checking palettes")
+             .conflicts_with_all(&["ascii", "blocks", "braille", "center", "iterm", "kitty", "montage", "pad", "quadrants", "scroll", "sixel", "window"]))
+        .arg(Arg::with_name("raw-indices")
+             .long("raw-indices")
+             .help("Prints the palette index picked for every pixel instead of drawing them, for comparing renders exactly")
+             .requires_all(&["width", "height"])
+             .conflicts_with_all(&["ascii", "blocks", "braille", "center", "hex", "iterm", "kitty", "montage", "pad", "quadrants", "scroll", "sixel", "window"]))
+        .arg(Arg::with_name("charset")
+             .long("charset")
+             .help("The characters to draw with in ASCII mode, from darkest to brightest")
+             .default_value(" .:-=+*#%@")
+             .value_name("CHARS")
+             .validator(is_charset))
+        .arg(Arg::with_name("sixel")
+             .long("sixel")
+             .help("Draws the image as Sixel graphics, at the resolution of the terminal's pixels instead of its cells")
+             .conflicts_with_all(&["ascii", "braille", "clipboard", "loop", "quadrants", "scroll", "window"]))
+        .arg(Arg::with_name("iterm")
+             .long("iterm")
+             .help("Draws the image with iTerm2's inline image protocol, in full color at the terminal's resolution")
+             .conflicts_with_all(&["ascii", "braille", "clipboard", "loop", "quadrants", "scroll", "sixel", "window"]))
+        .arg(Arg::with_name("kitty")
+             .long("kitty")
+             .help("Draws the image with Kitty's graphics protocol, in full color at the terminal's resolution")
+             .conflicts_with_all(&["ascii", "braille", "clipboard", "iterm", "loop", "quadrants", "scroll", "sixel", "window"]))
+        .arg(Arg::with_name("scroll")
+             .long("scroll")
+             .help("Fits only the height and scrolls the image horizontally across the terminal"))
+        .arg(Arg::with_name("scroll-delay")
+             .long("scroll-delay")
+             .help("The delay (in milliseconds) between each column of scrolling")
+             .default_value("50")
+             .value_name("MS")
+             .validator(is_u16))
+        .arg(Arg::with_name("loop")
+             .long("loop")
+             .help("Plays animations over and over instead of only once")
+             .conflicts_with("scroll"))
+        .arg(Arg::with_name("background")
+             .long("background")
+             .help("A color to composite transparent areas over")
+             .default_value("000000")
+             .value_name("RRGGBB")
+             .validator(is_color))
+        .arg(Arg::with_name("background-detect")
+             .long("background-detect")
+             .help("Asks the terminal for its background color to use in place of --background, falling back to black"))
+        .arg(Arg::with_name("bg-image")
+             .long("bg-image")
+             .help("An image to composite transparent areas over")
+             .conflicts_with_all(&["background", "bg-gradient"])
+             .value_name("FILE"))
+        .arg(Arg::with_name("bg-gradient")
+             .long("bg-gradient")
+             .help("A top-to-bottom gradient to composite transparent areas over")
+             .conflicts_with("background")
+             .value_name("RRGGBB,RRGGBB")
+             .validator(is_gradient))
+        .arg(Arg::with_name("frames")
+             .long("frames")
+             .help("Only plays the first this many frames of an animation")
+             .conflicts_with("frame-select")
+             .value_name("N")
+             .validator(is_positive))
+        .arg(Arg::with_name("fps")
+             .long("fps")
+             .help("The most frames per second to play an animation at, skipping any that come sooner")
+             .conflicts_with("frame-select")
+             .value_name("FPS")
+             .validator(is_fps))
+        .arg(Arg::with_name("format")
+             .long("format")
+             .help("Decodes every file as this format instead of going by its extension or contents")
+             .possible_values(FORMATS)
+             .value_name("FORMAT")
+             .conflicts_with("frame-select"))
+        .arg(Arg::with_name("frame-select")
+             .long("frame-select")
+             .help("Which image to render out of a multi-image ICO or TIFF file")
+             .value_name("largest|smallest|INDEX")
+             .validator(is_frame_select))
+        .arg(Arg::with_name("window")
+             .long("window")
+             .help("Only outputs the given region of cells out of the full render")
+             .conflicts_with("scroll")
+             .value_name("ROW,COL,ROWS,COLS")
+             .validator(is_window))
+        .arg(Arg::with_name("pad")
+             .long("pad")
+             .help("Surrounds the image with this many cells of --background, on every side or on each in turn")
+             .conflicts_with_all(&["iterm", "kitty", "scroll", "sixel"])
+             .value_name("N|TOP,RIGHT,BOTTOM,LEFT")
+             .validator(is_pad))
+        .arg(Arg::with_name("center")
+             .long("center")
+             .help("Centers the image across the terminal when it's narrower than it")
+             .conflicts_with_all(&["iterm", "kitty", "scroll", "sixel"]))
+        .arg(Arg::with_name("truncate")
+             .long("truncate")
+             .help("Cuts each row off at the edge of the terminal when the image is wider than it")
+             .conflicts_with_all(&["iterm", "kitty", "scroll", "sixel", "wrap"]))
+        .arg(Arg::with_name("wrap")
+             .long("wrap")
+             .help("Breaks each row onto the next line at the edge of the terminal when the image is wider than it")
+             .conflicts_with_all(&["iterm", "kitty", "scroll", "sixel"]))
+        .arg(Arg::with_name("set-background")
+             .long("set-background")
+             .help("Sets the image as the terminal's background instead of rendering it (iTerm2 only)"))
+        .arg(Arg::with_name("clipboard")
+             .long("clipboard")
+             .help("Also copies the rendered output to the clipboard using the OSC 52 escape"))
+        .arg(Arg::with_name("output")
+             .long("output")
+             .help("Writes the rendered output to a file instead of stdout")
+             .conflicts_with_all(&["loop", "scroll"])
+             .value_name("FILE"))
+        .arg(Arg::with_name("tee")
+             .long("tee")
+             .help("Also writes the rendered output to a file, may be given more than once")
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("FILE"))
+        .arg(Arg::with_name("trace")
+             .long("trace")
+             .help("Logs each processing stage with its dimensions and timing to stderr"))
+        .arg(Arg::with_name("verbose")
+             .long("verbose")
+             .help("Prints the terminal size, the size chosen for each image, the filter and the palette to stderr"))
+        .arg(Arg::with_name("quiet")
+             .long("quiet")
+             .help("Doesn't print warnings to stderr, only errors")
+             .conflicts_with("verbose"))
+        .arg(Arg::with_name("show-palette")
+             .long("show-palette")
+             .help("Prints every color in the palette with its index and hex value and exits"))
+        .arg(Arg::with_name("measure-aspect")
+             .long("measure-aspect")
+             .help("Prints the aspect ratios used to size the image and exits"))
+        .arg(Arg::with_name("watch")
+             .long("watch")
+             .help("Stays open and renders again whenever the terminal is resized, until interrupted")
+             .conflicts_with_all(&["clipboard", "dry-run", "loop", "measure-aspect", "output", "tee"]))
+        .arg(Arg::with_name("watch-file")
+             .long("watch-file")
+             .help("Stays open and renders again whenever the files change, until interrupted")
+             .conflicts_with_all(&["clipboard", "dry-run", "loop", "measure-aspect", "output", "tee"]))
+        .arg(Arg::with_name("dry-run")
+             .long("dry-run")
+             .help("Prints the size the image would be rendered at, as WIDTHxHEIGHT in cells, and exits")
+             .conflicts_with_all(&["clipboard", "measure-aspect", "output", "tee"]))
+        .arg(Arg::with_name("montage")
+             .long("montage")
+             .help("Lays the images out in a grid this many across, each fit inside a box --width by --height in size")
+             .conflicts_with_all(&["center", "iterm", "kitty", "scroll", "sixel"])
+             .value_name("COLS")
+             .validator(is_montage))
+        .arg(Arg::with_name("separator")
+             .long("separator")
+             .help("A line to print between images when rendering more than one")
+             .default_value("")
+             .value_name("TEXT"))
+        .arg(Arg::with_name("config")
+             .long("config")
+             .help("A TOML file of defaults for --filter, --dither, --colors and --cell-aspect, by default ~/.config/pic2term/config.toml")
+             .value_name("PATH"))
         .arg(Arg::with_name("file")
              .index(1)
-             .help("The file to render")
-             .required(true)
+             .help("The files to render, one after another")
+             .multiple(true)
              .value_name("FILE"))
-        .get_matches();
-
-    let file = matches.value_of("file").unwrap();
-    let img = image::open(file).expect("The file provided should actually exist").to_rgb();
-
-    let (w, h) = determine_size(img.width() as f32 / img.height() as f32,
-                                matches.value_of("width").map(str::parse).map(Result::unwrap),
-                                matches.value_of("height").map(str::parse).map(Result::unwrap))
-        .expect("Unable to determine terminal size, pass --width or --height flags");
-    let filter = determine_filter(matches.value_of("filter").unwrap());
-
-    // Cut off the first 16 indices since users tend to customize those
-    let indices = dither(imageops::resize(&img, w as u32, h as u32, filter), &ANSI_COLORS[16..]);
-    // Bump the indices we got back up by 16 to adjust
-    let rows = indices.into_iter().map(|n| (n + 16) as u8).chunks_lazy(w as usize * 2);
-    for mut pair in rows.into_iter() {
-        let upper = pair.by_ref().take(w as usize).collect::<Vec<_>>();
-        let mut lower = pair.take(w as usize).peekable();
-
-        println!("{}", ANSIStrings(&if lower.peek().is_some() {
-            lower.map(Colour::Fixed)
-                .zip(upper.into_iter().map(Colour::Fixed))
-                .map(|(lower, upper)| lower.on(upper))
-                .map(|c| c.paint("\u{2584}"))
-                .collect::<Vec<_>>()
+}
+// A flag's value as given outright, or else from the config file, or else its default
+fn setting(matches: &ArgMatches, config: &Config, name: &str) -> Option<String> {
+    match config.get(name) {
+        Some(value) if matches.occurrences_of(name) == 0 => Some(value),
+        _ => matches.value_of(name).map(String::from),
+    }
+}
+
+fn main() {
+    let matches = app().get_matches();
+
+    let mut logger = env_logger::Builder::from_default_env();
+    if matches.is_present("trace") {
+        logger.filter_module("pic2term", LevelFilter::Trace);
+    }
+    logger.init();
+
+    // Flags given outright win over the config file, which wins over the built-in defaults
+    let config = load_config(matches.value_of("config")).unwrap_or_else(|e| fail(&e));
+    let setting = |name: &str| setting(&matches, &config, name);
+    let filter = setting("filter").unwrap();
+    let dither = setting("dither").unwrap();
+    // Either one asking for a way of dithering counts as asking for dithering at all
+    let dither_given = matches.occurrences_of("dither") > 0 || config.get("dither").is_some();
+
+    // Showing the palette is the one thing that doesn't need any files
+    let files = matches.values_of("file").map_or_else(Vec::new, |files| files.collect::<Vec<_>>());
+    if files.is_empty() && !matches.is_present("show-palette") {
+        fail("no files were given to render");
+    }
+    if matches.is_present("set-background") {
+        let file = files.first().unwrap_or_else(|| fail("--set-background needs a file"));
+        if let Err(e) = set_background(file) {
+            fail(&format!("unable to set the background: {}", e));
+        }
+        return;
+    }
+
+    // Preserving the palette means never blending pixels together, be it while resizing or dithering
+    let preserve_palette = matches.is_present("preserve-palette");
+    let lut = matches.value_of("lut").map(|path| {
+        fs::read_to_string(path).map_err(error_message).and_then(|text| parse_lut(&text))
+            .unwrap_or_else(|e| fail(&format!("cannot load LUT '{}': {}", path, e)))
+    });
+    let palette = if matches.is_present("truecolor") {
+        Palette::Truecolor
+    } else if let Some(path) = matches.value_of("palette") {
+        Palette::Custom(fs::read_to_string(path).map_err(error_message).and_then(|text| parse_palette(&text))
+            .unwrap_or_else(|e| fail(&format!("cannot load palette '{}': {}", path, e))))
+    } else if let Some(bits) = matches.value_of("cube-bits") {
+        Palette::Rgb(cube_palette(bits.parse().unwrap()))
+    } else {
+        // Truecolor can't be dithered, so it's only detected when nothing asks for dithering
+        let dithering = matches.is_present("kernel") || matches.is_present("preserve-palette") || dither_given;
+        // Raw indices have to come out the same wherever they're rendered
+        let colors = setting("colors");
+        let colors = match colors.as_deref() {
+            Some(colors) => colors,
+            None if matches.is_present("raw-indices") => "256",
+            None => detect_colors(),
+        };
+        match colors {
+            "truecolor" if !dithering => Palette::Truecolor,
+            "88" => Palette::Indexed(&RXVT_COLORS),
+            "16" => Palette::Indexed(&ANSI_COLORS[..16]),
+            _ => Palette::Indexed(&ANSI_COLORS),
+        }
+    };
+    if matches.is_present("show-palette") {
+        // Truecolor and generated cubes have no palette of their own, so it's the 256 colors that get shown
+        let colors = match palette {
+            Palette::Indexed(colors) => colors,
+            Palette::Custom(ref colors) => colors,
+            _ => &ANSI_COLORS[..],
+        };
+        if let Err(e) = write_lines(&mut io::stdout().lock(), &render_palette(colors)) {
+            fail(&format!("cannot write to stdout: {}", error_message(e)));
+        }
+        return;
+    }
+    // NO_COLOR asks for output without any color, unless a way of drawing in color is asked for outright
+    let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) &&
+        !["blend", "blocks", "colors", "cube-bits", "iterm", "kitty", "palette", "quadrants", "sixel", "truecolor"].iter().any(|&arg| matches.is_present(arg));
+    let opts = RenderOptions {
+        width: matches.value_of("width").map(|s| parse_dimension(s).unwrap()),
+        height: matches.value_of("height").map(|s| parse_dimension(s).unwrap()),
+        reserve_rows: matches.value_of("reserve-rows").unwrap().parse().unwrap(),
+        cell_aspect: setting("cell-aspect").unwrap().parse().unwrap(),
+        filter: if preserve_palette { FilterType::Nearest } else { determine_filter(&filter) },
+        preserve_palette,
+        downscale_steps: if preserve_palette { 0 } else { matches.value_of("downscale-steps").unwrap().parse().unwrap() },
+        kernel: if preserve_palette {
+            Vec::new()
+        } else {
+            matches.value_of("kernel").map(|s| parse_kernel(s).unwrap())
+                .unwrap_or_else(|| determine_dither(&dither).kernel().to_vec())
+        },
+        no_dither: preserve_palette || matches.is_present("no-dither"),
+        // Anything past either end is treated as that end
+        strength: matches.value_of("strength").unwrap().parse::<f32>().unwrap().clamp(0.0, 1.0),
+        serpentine: matches.is_present("serpentine"),
+        bayer: if preserve_palette {
+            None
+        } else {
+            determine_dither(&dither).bayer()
+        },
+        palette,
+        gamma: matches.value_of("gamma").unwrap().parse().unwrap(),
+        colorspace: match matches.value_of("colorspace").unwrap() {
+            "lab" => ColorSpace::Lab,
+            _ => ColorSpace::Rgb,
+        },
+        rotate: matches.value_of("rotate").map_or(0, |s| s.parse().unwrap()),
+        autocrop: matches.is_present("autocrop-smart"),
+        dejpeg: matches.is_present("dejpeg"),
+        equalize: matches.is_present("equalize"),
+        lut,
+        brightness: matches.value_of("brightness").unwrap().parse().unwrap(),
+        contrast: matches.value_of("contrast").unwrap().parse().unwrap(),
+        grayscale: matches.is_present("grayscale"),
+        luma: match matches.value_of("luma").unwrap() {
+            "rec601" => Luma::Rec601,
+            "average" => Luma::Average,
+            _ => Luma::Rec709,
+        },
+        invert: matches.is_present("invert"),
+        flip_h: matches.is_present("flip-h"),
+        flip_v: matches.is_present("flip-v"),
+        glyphs: if matches.is_present("quadrants") {
+            Glyphs::Quadrants
+        } else if matches.is_present("braille") {
+            Glyphs::Braille
+        } else if matches.is_present("ascii") {
+            Glyphs::Ascii
+        } else if matches.is_present("hex") {
+            Glyphs::Hex
+        } else if matches.is_present("raw-indices") {
+            Glyphs::Indices
+        } else if matches.is_present("blend") {
+            Glyphs::Blended
+        } else if matches.value_of("blocks") == Some("full") {
+            Glyphs::FullBlocks
+        } else if no_color {
+            Glyphs::Ascii
         } else {
-            upper.into_iter().map(Colour::Fixed)
-                .map(|c| c.paint("\u{2580}"))
-                .collect::<Vec<_>>()
-        }));
-    }
-}
-
-static ANSI_COLORS: [[u8; 3]; 256] = [
-    [0x00, 0x00, 0x00], [0x80, 0x00, 0x00], [0x00, 0x80, 0x00],
-    [0x80, 0x80, 0x00], [0x00, 0x00, 0x80], [0x80, 0x00, 0x80],
-    [0x00, 0x80, 0x80], [0xc0, 0xc0, 0xc0], [0x80, 0x80, 0x80],
-    [0xff, 0x00, 0x00], [0x00, 0xff, 0x00], [0xff, 0xff, 0x00],
-    [0x00, 0x00, 0xff], [0xff, 0x00, 0xff], [0x00, 0xff, 0xff],
-    [0xff, 0xff, 0xff], [0x00, 0x00, 0x00], [0x00, 0x00, 0x5f],
-    [0x00, 0x00, 0x87], [0x00, 0x00, 0xaf], [0x00, 0x00, 0xd7],
-    [0x00, 0x00, 0xff], [0x00, 0x5f, 0x00], [0x00, 0x5f, 0x5f],
-    [0x00, 0x5f, 0x87], [0x00, 0x5f, 0xaf], [0x00, 0x5f, 0xd7],
-    [0x00, 0x5f, 0xff], [0x00, 0x87, 0x00], [0x00, 0x87, 0x5f],
-    [0x00, 0x87, 0x87], [0x00, 0x87, 0xaf], [0x00, 0x87, 0xd7],
-    [0x00, 0x87, 0xff], [0x00, 0xaf, 0x00], [0x00, 0xaf, 0x5f],
-    [0x00, 0xaf, 0x87], [0x00, 0xaf, 0xaf], [0x00, 0xaf, 0xd7],
-    [0x00, 0xaf, 0xff], [0x00, 0xd7, 0x00], [0x00, 0xd7, 0x5f],
-    [0x00, 0xd7, 0x87], [0x00, 0xd7, 0xaf], [0x00, 0xd7, 0xd7],
-    [0x00, 0xd7, 0xff], [0x00, 0xff, 0x00], [0x00, 0xff, 0x5f],
-    [0x00, 0xff, 0x87], [0x00, 0xff, 0xaf], [0x00, 0xff, 0xd7],
-    [0x00, 0xff, 0xff], [0x5f, 0x00, 0x00], [0x5f, 0x00, 0x5f],
-    [0x5f, 0x00, 0x87], [0x5f, 0x00, 0xaf], [0x5f, 0x00, 0xd7],
-    [0x5f, 0x00, 0xff], [0x5f, 0x5f, 0x00], [0x5f, 0x5f, 0x5f],
-    [0x5f, 0x5f, 0x87], [0x5f, 0x5f, 0xaf], [0x5f, 0x5f, 0xd7],
-    [0x5f, 0x5f, 0xff], [0x5f, 0x87, 0x00], [0x5f, 0x87, 0x5f],
-    [0x5f, 0x87, 0x87], [0x5f, 0x87, 0xaf], [0x5f, 0x87, 0xd7],
-    [0x5f, 0x87, 0xff], [0x5f, 0xaf, 0x00], [0x5f, 0xaf, 0x5f],
-    [0x5f, 0xaf, 0x87], [0x5f, 0xaf, 0xaf], [0x5f, 0xaf, 0xd7],
-    [0x5f, 0xaf, 0xff], [0x5f, 0xd7, 0x00], [0x5f, 0xd7, 0x5f],
-    [0x5f, 0xd7, 0x87], [0x5f, 0xd7, 0xaf], [0x5f, 0xd7, 0xd7],
-    [0x5f, 0xd7, 0xff], [0x5f, 0xff, 0x00], [0x5f, 0xff, 0x5f],
-    [0x5f, 0xff, 0x87], [0x5f, 0xff, 0xaf], [0x5f, 0xff, 0xd7],
-    [0x5f, 0xff, 0xff], [0x87, 0x00, 0x00], [0x87, 0x00, 0x5f],
-    [0x87, 0x00, 0x87], [0x87, 0x00, 0xaf], [0x87, 0x00, 0xd7],
-    [0x87, 0x00, 0xff], [0x87, 0x5f, 0x00], [0x87, 0x5f, 0x5f],
-    [0x87, 0x5f, 0x87], [0x87, 0x5f, 0xaf], [0x87, 0x5f, 0xd7],
-    [0x87, 0x5f, 0xff], [0x87, 0x87, 0x00], [0x87, 0x87, 0x5f],
-    [0x87, 0x87, 0x87], [0x87, 0x87, 0xaf], [0x87, 0x87, 0xd7],
-    [0x87, 0x87, 0xff], [0x87, 0xaf, 0x00], [0x87, 0xaf, 0x5f],
-    [0x87, 0xaf, 0x87], [0x87, 0xaf, 0xaf], [0x87, 0xaf, 0xd7],
-    [0x87, 0xaf, 0xff], [0x87, 0xd7, 0x00], [0x87, 0xd7, 0x5f],
-    [0x87, 0xd7, 0x87], [0x87, 0xd7, 0xaf], [0x87, 0xd7, 0xd7],
-    [0x87, 0xd7, 0xff], [0x87, 0xff, 0x00], [0x87, 0xff, 0x5f],
-    [0x87, 0xff, 0x87], [0x87, 0xff, 0xaf], [0x87, 0xff, 0xd7],
-    [0x87, 0xff, 0xff], [0xaf, 0x00, 0x00], [0xaf, 0x00, 0x5f],
-    [0xaf, 0x00, 0x87], [0xaf, 0x00, 0xaf], [0xaf, 0x00, 0xd7],
-    [0xaf, 0x00, 0xff], [0xaf, 0x5f, 0x00], [0xaf, 0x5f, 0x5f],
-    [0xaf, 0x5f, 0x87], [0xaf, 0x5f, 0xaf], [0xaf, 0x5f, 0xd7],
-    [0xaf, 0x5f, 0xff], [0xaf, 0x87, 0x00], [0xaf, 0x87, 0x5f],
-    [0xaf, 0x87, 0x87], [0xaf, 0x87, 0xaf], [0xaf, 0x87, 0xd7],
-    [0xaf, 0x87, 0xff], [0xaf, 0xaf, 0x00], [0xaf, 0xaf, 0x5f],
-    [0xaf, 0xaf, 0x87], [0xaf, 0xaf, 0xaf], [0xaf, 0xaf, 0xd7],
-    [0xaf, 0xaf, 0xff], [0xaf, 0xd7, 0x00], [0xaf, 0xd7, 0x5f],
-    [0xaf, 0xd7, 0x87], [0xaf, 0xd7, 0xaf], [0xaf, 0xd7, 0xd7],
-    [0xaf, 0xd7, 0xff], [0xaf, 0xff, 0x00], [0xaf, 0xff, 0x5f],
-    [0xaf, 0xff, 0x87], [0xaf, 0xff, 0xaf], [0xaf, 0xff, 0xd7],
-    [0xaf, 0xff, 0xff], [0xd7, 0x00, 0x00], [0xd7, 0x00, 0x5f],
-    [0xd7, 0x00, 0x87], [0xd7, 0x00, 0xaf], [0xd7, 0x00, 0xd7],
-    [0xd7, 0x00, 0xff], [0xd7, 0x5f, 0x00], [0xd7, 0x5f, 0x5f],
-    [0xd7, 0x5f, 0x87], [0xd7, 0x5f, 0xaf], [0xd7, 0x5f, 0xd7],
-    [0xd7, 0x5f, 0xff], [0xd7, 0x87, 0x00], [0xd7, 0x87, 0x5f],
-    [0xd7, 0x87, 0x87], [0xd7, 0x87, 0xaf], [0xd7, 0x87, 0xd7],
-    [0xd7, 0x87, 0xff], [0xd7, 0xaf, 0x00], [0xd7, 0xaf, 0x5f],
-    [0xd7, 0xaf, 0x87], [0xd7, 0xaf, 0xaf], [0xd7, 0xaf, 0xd7],
-    [0xd7, 0xaf, 0xff], [0xd7, 0xd7, 0x00], [0xd7, 0xd7, 0x5f],
-    [0xd7, 0xd7, 0x87], [0xd7, 0xd7, 0xaf], [0xd7, 0xd7, 0xd7],
-    [0xd7, 0xd7, 0xff], [0xd7, 0xff, 0x00], [0xd7, 0xff, 0x5f],
-    [0xd7, 0xff, 0x87], [0xd7, 0xff, 0xaf], [0xd7, 0xff, 0xd7],
-    [0xd7, 0xff, 0xff], [0xff, 0x00, 0x00], [0xff, 0x00, 0x5f],
-    [0xff, 0x00, 0x87], [0xff, 0x00, 0xaf], [0xff, 0x00, 0xd7],
-    [0xff, 0x00, 0xff], [0xff, 0x5f, 0x00], [0xff, 0x5f, 0x5f],
-    [0xff, 0x5f, 0x87], [0xff, 0x5f, 0xaf], [0xff, 0x5f, 0xd7],
-    [0xff, 0x5f, 0xff], [0xff, 0x87, 0x00], [0xff, 0x87, 0x5f],
-    [0xff, 0x87, 0x87], [0xff, 0x87, 0xaf], [0xff, 0x87, 0xd7],
-    [0xff, 0x87, 0xff], [0xff, 0xaf, 0x00], [0xff, 0xaf, 0x5f],
-    [0xff, 0xaf, 0x87], [0xff, 0xaf, 0xaf], [0xff, 0xaf, 0xd7],
-    [0xff, 0xaf, 0xff], [0xff, 0xd7, 0x00], [0xff, 0xd7, 0x5f],
-    [0xff, 0xd7, 0x87], [0xff, 0xd7, 0xaf], [0xff, 0xd7, 0xd7],
-    [0xff, 0xd7, 0xff], [0xff, 0xff, 0x00], [0xff, 0xff, 0x5f],
-    [0xff, 0xff, 0x87], [0xff, 0xff, 0xaf], [0xff, 0xff, 0xd7],
-    [0xff, 0xff, 0xff], [0x08, 0x08, 0x08], [0x12, 0x12, 0x12],
-    [0x1c, 0x1c, 0x1c], [0x26, 0x26, 0x26], [0x30, 0x30, 0x30],
-    [0x3a, 0x3a, 0x3a], [0x44, 0x44, 0x44], [0x4e, 0x4e, 0x4e],
-    [0x58, 0x58, 0x58], [0x60, 0x60, 0x60], [0x66, 0x66, 0x66],
-    [0x76, 0x76, 0x76], [0x80, 0x80, 0x80], [0x8a, 0x8a, 0x8a],
-    [0x94, 0x94, 0x94], [0x9e, 0x9e, 0x9e], [0xa8, 0xa8, 0xa8],
-    [0xb2, 0xb2, 0xb2], [0xbc, 0xbc, 0xbc], [0xc6, 0xc6, 0xc6],
-    [0xd0, 0xd0, 0xd0], [0xda, 0xda, 0xda], [0xe4, 0xe4, 0xe4],
-    [0xee, 0xee, 0xee],
-];
+            Glyphs::HalfBlocks
+        },
+        threshold: matches.value_of("threshold").unwrap().parse().unwrap(),
+        charset: String::from(matches.value_of("charset").unwrap()),
+        negative: matches.is_present("negative"),
+        threads: matches.value_of("threads").unwrap().parse().unwrap(),
+    };
+    if opts.grayscale && !opts.palette.has_grays() {
+        fail("palette has no neutral colors to render in grayscale with");
+    }
+
+    let graphics = if matches.is_present("sixel") {
+        Some(render_sixel as Graphics)
+    } else if matches.is_present("iterm") {
+        Some(render_iterm as Graphics)
+    } else if matches.is_present("kitty") {
+        Some(render_kitty as Graphics)
+    } else {
+        None
+    };
+    let scroll = matches.is_present("scroll");
+    let pad = matches.value_of("pad").map_or([0; 4], |pad| parse_pad(pad).unwrap());
+    let quiet = matches.is_present("quiet");
+    // Only asked for when no color is given outright
+    let background = if matches.is_present("background-detect") && matches.occurrences_of("background") == 0 {
+        query_background().unwrap_or_else(|| {
+            if !quiet {
+                eprintln!("pic2term: warning: the terminal didn't report its background color, using black");
+            }
+            [0, 0, 0]
+        })
+    } else {
+        parse_color(matches.value_of("background").unwrap()).unwrap()
+    };
+    let fill = background;
+    let window = matches.value_of("window").map(|window| parse_window(window).unwrap());
+
+    // Writing to a file takes the place of showing it
+    let to_stdout = matches.value_of("output").is_none();
+    let max_w = matches.value_of("max-width").unwrap().parse().unwrap();
+    let max_h: u16 = matches.value_of("max-height").unwrap().parse().unwrap();
+    let verbose = matches.is_present("verbose");
+    if verbose {
+        match termsize::get() {
+            Some(size) => eprintln!("pic2term: terminal: {}x{} cells", size.cols, size.rows),
+            None => eprintln!("pic2term: terminal: unknown size"),
+        }
+        eprintln!("pic2term: filter: {}", if preserve_palette { "nearest" } else { &filter });
+        match opts.palette {
+            Palette::Indexed(colors) => eprintln!("pic2term: palette: {} colors", colors.len()),
+            Palette::Custom(ref colors) | Palette::Rgb(ref colors) => eprintln!("pic2term: palette: {} colors", colors.len()),
+            Palette::Truecolor => eprintln!("pic2term: palette: truecolor"),
+        }
+    }
+
+    let terminal_out = to_stdout && io::stdout().is_terminal();
+    if terminal_out {
+        reset_on_interrupt();
+    }
+
+    // Watching renders everything over again whenever the terminal changes size, or the files do
+    let watch = matches.is_present("watch");
+    if watch {
+        watch_resizes().unwrap_or_else(|e| fail(&e));
+    }
+    let watch_file = matches.is_present("watch-file");
+    let watched = if watch_file { files.clone() } else { Vec::new() };
+    if let Some(url) = watched.iter().find(|&&file| is_url(file)) {
+        fail(&format!("cannot watch '{}' for changes, only local files can be", url));
+    }
+    let rendered = loop {
+        let stamps = modified(&watched);
+        if watch || watch_file {
+            print!("\x1b[2J\x1b[H");
+        }
+
+        // Centering needs to know how wide the terminal is, but goes without when it can't be found
+        let center = if matches.is_present("center") { termsize::get().map(|size| size.cols) } else { None };
+        // Rows wider than the terminal would wrap wherever it happened to, so they get cut or broken at its edge
+        // instead, which is left alone too when the terminal's width can't be found
+        let overflow = matches.is_present("truncate") || matches.is_present("wrap");
+        let edge = if overflow { termsize::get().map(|size| size.cols as usize) } else { None };
+        let fit_edge = |lines| match edge {
+            Some(cols) if matches.is_present("wrap") => wrap_lines(lines, cols),
+            Some(cols) => truncate_lines(lines, cols),
+            None => lines,
+        };
+
+        // Every image in a montage gets the same box, --width by --height or as big as fits across
+        let montage_cols = matches.value_of("montage").map(|cols| cols.parse::<u16>().unwrap());
+        let tile = montage_cols.map(|cols| {
+            let terminal = termsize::get();
+            let w = match opts.width {
+                Some(width) => width.resolve(terminal.as_ref().map(|size| size.cols)),
+                None => terminal.as_ref().map(|size| (size.cols.saturating_sub(cols - 1) / cols).max(1)),
+            }.unwrap_or_else(|| fail("unable to determine the terminal size, pass --width without a percentage"));
+            // Square boxes unless told otherwise, which are as many pixels tall as wide when every cell
+            // is two pixels tall and twice as tall as it's wide
+            let h = match opts.height {
+                Some(height) => height.resolve(terminal.as_ref().map(|size| size.rows)).map(|h| h.saturating_mul(2)),
+                None => Some((w as f32 * 2.0 / opts.cell_aspect).round().clamp(1.0, u16::MAX as f32) as u16),
+            }.unwrap_or_else(|| fail("unable to determine the terminal size, pass --height without a percentage"));
+            (w, h)
+        });
+        let mut tiles = Vec::new();
+
+        // Everything rendered, for whatever it gets saved or copied to once it's all done
+        let mut rendered = String::new();
+
+        for (i, &file) in files.iter().enumerate() {
+            if i > 0 && tile.is_none() && !matches.is_present("dry-run") {
+                let separator = format!("{}\n", matches.value_of("separator").unwrap());
+                if to_stdout {
+                    if let Err(e) = io::stdout().write_all(separator.as_bytes()) {
+                        fail(&format!("cannot write to stdout: {}", error_message(e)));
+                    }
+                }
+                rendered.push_str(&separator);
+            }
+            let start = Instant::now();
+            // Animations get played back, which needs all of their frames rather than just the first
+            let loaded = read_source(file).and_then(|data| {
+                // A format given outright is the only one the data gets taken for, animated or not
+                let frames = match (matches.value_of("frame-select"), matches.value_of("format")) {
+                    (Some(_), _) => None,
+                    (None, Some("gif")) => gif_frames(&data, file)?,
+                    (None, Some("webp")) => webp_frames(&data, file)?,
+                    (None, Some(_)) => None,
+                    (None, None) => animation_frames(&data, file)?,
+                }.filter(|frames| !frames.is_empty());
+                let stretched = if matches.is_present("stretch-levels") && matches.value_of("format").is_none_or(|format| format == "png") {
+                    open_deep_png(&data, true)
+                } else {
+                    None
+                };
+                let source = if let Some(ref frames) = frames {
+                    DynamicImage::ImageRgba8(frames[0].0.clone())
+                } else if let Some(stretched) = stretched {
+                    stretched.map_err(|e| format!("cannot decode '{}': {}", file, e))?
+                } else if let Some(format) = matches.value_of("format") {
+                    open_as(&data, file, format)?
+                } else if let Some(select) = matches.value_of("frame-select") {
+                    open_frame(&data, file, parse_frame_select(select).unwrap())?
+                } else {
+                    open_data(&data, file)?
+                };
+                Ok((data, frames, source))
+            });
+            let (data, frames, source) = match loaded {
+                Ok(loaded) => loaded,
+                // A file that's still being written out might not load yet, so wait for it to change again
+                Err(e) if watch_file => {
+                    eprintln!("pic2term: {}", e);
+                    continue;
+                },
+                Err(e) => fail(&e),
+            };
+            // Photos are often stored sideways with a note saying which way up they go
+            let source = match exif_orientation(&data) {
+                Some(orientation) if !matches.is_present("no-autorotate") => {
+                    trace!("exif orientation: {}", orientation);
+                    orient(source, orientation)
+                },
+                _ => source,
+            };
+            // Grayscale images only need the palette's grays, and ones that are only ever black or white
+            // are already in the palette, so there's nothing for dithering to do but leave speckles
+            let dithering = matches.is_present("kernel") || dither_given;
+            let opts = match source {
+                // A palette without any grays has nothing to match them against, so it keeps all its colors
+                DynamicImage::ImageLuma8(ref gray) if !matches!(opts.palette, Palette::Truecolor) && opts.palette.has_grays() => {
+                    if !dithering && gray.pixels().all(|p| p.data[0] == 0 || p.data[0] == 255) {
+                        if verbose {
+                            eprintln!("pic2term: '{}' is black and white, mapping it without dithering", file);
+                        }
+                        RenderOptions { grayscale: true, no_dither: true, ..opts.clone() }
+                    } else {
+                        RenderOptions { grayscale: true, ..opts.clone() }
+                    }
+                },
+                _ => opts.clone(),
+            };
+            // Text output is there to be read rather than watched, so it only ever shows the first frame
+            let animation = frames.map(|mut frames| {
+                if let Some(count) = matches.value_of("frames") {
+                    frames.truncate(count.parse().unwrap());
+                }
+                match matches.value_of("fps") {
+                    Some(fps) => cap_fps(frames, fps.parse().unwrap()),
+                    None => frames,
+                }
+            }).filter(|frames| frames.len() > 1).filter(|_| !matches.is_present("hex") && !matches.is_present("raw-indices"));
+            let (source_w, source_h) = source.dimensions();
+            trace!("load: {}x{} from '{}' in {:?}", source_w, source_h, file, start.elapsed());
+
+            let start = Instant::now();
+            let backdrop = if let Some(bg_file) = matches.value_of("bg-image") {
+                let backdrop = open_image(bg_file).unwrap_or_else(|e| fail(&e)).to_rgb();
+                imageops::resize(&backdrop, source_w, source_h, FilterType::Triangle)
+            } else if let Some(colors) = matches.value_of("bg-gradient") {
+                let (top, bottom) = parse_gradient(colors).unwrap();
+                gradient(source_w, source_h, top, bottom)
+            } else {
+                RgbImage::from_pixel(source_w, source_h, Rgb { data: background })
+            };
+            let crop = matches.value_of("crop").map(|crop| parse_crop(crop).unwrap());
+            if let Some([x, y, w, h]) = crop {
+                if x as u64 + w as u64 > source_w as u64 || y as u64 + h as u64 > source_h as u64 {
+                    fail(&format!("the region {}x{} at {},{} doesn't fit inside the {}x{} image", w, h, x, y, source_w, source_h));
+                }
+            }
+            let flatten = |source: &DynamicImage| {
+                let mut img = composite(&source.to_rgba(), &backdrop);
+                match crop {
+                    Some([x, y, w, h]) => imageops::crop(&mut img, x, y, w, h).to_image(),
+                    None => img,
+                }
+            };
+            let img = flatten(&source);
+            trace!("composite: {}x{} -> {}x{} in {:?}", source_w, source_h, img.width(), img.height(), start.elapsed());
+
+            let img = prepare(img, &opts);
+            let aspect = img.width() as f32 / img.height() as f32;
+            if matches.is_present("measure-aspect") {
+                println!("source aspect:    {} ({}x{} pixels)", aspect, img.width(), img.height());
+                println!("cell aspect:      {} (each cell is two pixels tall with half-blocks)", opts.cell_aspect);
+                println!("effective aspect: {} (columns per row of cells)", aspect * opts.cell_aspect);
+                continue;
+            }
+
+            let (w, h) = if tile.is_some() {
+                tile
+            } else if scroll {
+                // Only fit the height, letting the width overflow the terminal so it can be scrolled
+                let terminal_h = termsize::get().map(|size| Dimension::Cells(size.rows.saturating_sub(opts.reserve_rows).max(1)));
+                determine_size(aspect, None, opts.height.or(terminal_h), opts.reserve_rows, opts.cell_aspect, termsize::get().as_ref())
+            } else {
+                // Padding comes out of the size asked for, leaving that much less for the image itself
+                let [top, right, bottom, left] = pad;
+                let width = opts.width.map(|width| {
+                    width.resolve(termsize::get().map(|size| size.cols))
+                        .map_or(width, |n| Dimension::Cells(n.saturating_sub(left.saturating_add(right)).max(1)))
+                });
+                let height = opts.height.map(|height| {
+                    height.resolve(termsize::get().map(|size| size.rows))
+                        .map_or(height, |n| Dimension::Cells(n.saturating_sub(top.saturating_add(bottom)).max(1)))
+                });
+                determine_size(aspect, width, height, opts.reserve_rows, opts.cell_aspect, termsize::get().as_ref())
+            }.unwrap_or_else(|| fail("unable to determine the terminal size, pass --width or --height without percentages"));
+            trace!("size: {}x{} at aspect {}", w, h, aspect);
+            let (w, h) = match matches.value_of("scale") {
+                Some(scale) => {
+                    let scale = scale.parse::<f32>().unwrap();
+                    let scaled = |n: u16| (n as f32 * scale).round().clamp(1.0, u16::MAX as f32) as u16;
+                    (scaled(w), scaled(h))
+                },
+                None => (w, h),
+            };
+            // A typo'd size shouldn't be able to flood the terminal or run out of memory
+            let (w, h) = match clamp_size((w, h), (max_w, max_h.saturating_mul(2))) {
+                (clamped_w, clamped_h) if (clamped_w, clamped_h) != (w, h) => {
+                    if verbose {
+                        eprintln!("pic2term: clamped {}x{} cells to {}x{} for '{}'", w, h.div_ceil(2), clamped_w, clamped_h.div_ceil(2), file);
+                    }
+                    (clamped_w, clamped_h)
+                },
+                size => size,
+            };
+            if verbose {
+                eprintln!("pic2term: size: {}x{} cells for '{}'", w, h.div_ceil(2), file);
+            }
+            // Picking a filter automatically goes by how far across the image gets scaled
+            let opts = if filter == "auto" && !preserve_palette {
+                let target_w = w as u32 * match (graphics, opts.glyphs) {
+                    (Some(_), _) => CELL_PIXELS[0],
+                    (None, Glyphs::Quadrants) | (None, Glyphs::Braille) => 2,
+                    _ => 1,
+                };
+                let picked = auto_filter(&img, target_w);
+                if verbose {
+                    eprintln!("pic2term: filter: {} for '{}'", picked, file);
+                }
+                RenderOptions { filter: determine_filter(picked), ..opts }
+            } else {
+                opts
+            };
+            if !quiet && opts.width.is_none() && opts.height.is_none() && !scroll && tile.is_none() && (w < 10 || h < 10) {
+                // Still render it, but whatever comes out is unlikely to be recognizable
+                eprintln!("pic2term: warning: the terminal only fits a {}x{} render, pass --width or --height for a larger one",
+                          w, h.div_ceil(2));
+            }
+            if matches.is_present("dry-run") {
+                println!("{}x{}", w, h.div_ceil(2));
+                continue;
+            }
+
+            // Fitting pads every frame out to the shape of the box instead of stretching it, and filling
+            // crops it down to that shape
+            let fit = matches.is_present("fit") || tile.is_some();
+            // The box's shape as it's shown, which is only its shape in pixels when they come out square
+            let shape = w as f32 / h as f32 * 2.0 / opts.cell_aspect;
+            let boxed = |img: RgbImage| if fit {
+                letterbox(img, shape, fill)
+            } else if matches.is_present("fill") {
+                crop_to_aspect(img, shape)
+            } else {
+                img
+            };
+
+            if let Some(graphics) = graphics {
+                // Graphics can't be animated either, so an animation only shows its first frame
+                let graphics = graphics(boxed(img), w, h, &opts);
+                if to_stdout {
+                    if let Err(e) = io::stdout().write_all(graphics.as_bytes()) {
+                        fail(&format!("cannot write to stdout: {}", error_message(e)));
+                    }
+                }
+                rendered.push_str(&graphics);
+                continue;
+            }
+
+            let render_frame = |img: RgbImage| {
+                let lines = render_sized(boxed(img), w, h, &opts);
+                let lines = match window {
+                    Some(window) => window_lines(lines, window),
+                    None => lines,
+                };
+                let lines = if pad == [0; 4] { lines } else { pad_lines(lines, pad, fill, &opts) };
+                let lines = match center {
+                    Some(cols) => center_lines(lines, cols as usize),
+                    None => lines,
+                };
+                // A montage only meets the edge once its tiles are laid out
+                if tile.is_some() { lines } else { fit_edge(lines) }
+            };
+            let lines = render_frame(img);
+            if tile.is_some() {
+                // Montages only show each image's first frame, once they've all been rendered
+                tiles.push(lines);
+                continue;
+            }
+
+            if scroll {
+                // In scroll mode --width picks the size of the visible window instead
+                let terminal_cols = termsize::get().map(|size| size.cols);
+                let cols = opts.width.map_or(terminal_cols, |desired_w| desired_w.resolve(terminal_cols))
+                    .unwrap_or_else(|| fail("unable to determine the terminal size, pass --width without a percentage"));
+                let delay = Duration::from_millis(matches.value_of("scroll-delay").unwrap().parse().unwrap());
+                scroll_lines(&lines, cols as usize, delay);
+            } else if let Some(frames) = animation.filter(|_| to_stdout) {
+                // Render everything up front so slow frames don't throw the timing off. The first frame
+                // is what was already rendered, which is also what gets copied anywhere else.
+                let mut frames_lines = Vec::with_capacity(frames.len());
+                for (i, (frame, delay)) in frames.into_iter().enumerate() {
+                    let lines = if i == 0 { lines.clone() } else { render_frame(prepare(flatten(&DynamicImage::ImageRgba8(frame)), &opts)) };
+                    frames_lines.push((lines, delay));
+                }
+                if let Err(e) = play_frames(&frames_lines, matches.is_present("loop")) {
+                    fail(&format!("cannot write to stdout: {}", error_message(e)));
+                }
+            } else if to_stdout {
+                let stdout = io::stdout();
+                if let Err(e) = write_lines(&mut stdout.lock(), &lines) {
+                    fail(&format!("cannot write to stdout: {}", error_message(e)));
+                }
+            }
+            // A file can't be animated, so only an animation's first frame ends up in it
+            rendered.push_str(&lines_to_string(&lines));
+        }
+
+        if let Some(cols) = montage_cols {
+            let lines = fit_edge(montage(tiles, cols as usize));
+            if to_stdout {
+                let stdout = io::stdout();
+                if let Err(e) = write_lines(&mut stdout.lock(), &lines) {
+                    fail(&format!("cannot write to stdout: {}", error_message(e)));
+                }
+            }
+            rendered = lines_to_string(&lines);
+        }
+
+        if !watch && !watch_file {
+            break rendered;
+        }
+        wait_for_change(&watched, stamps);
+    };
+    // Each row resets its own colors already, but only when it ends on some, so make sure nothing's left over
+    if terminal_out {
+        print!("\x1b[0m");
+        if let Err(e) = io::stdout().flush() {
+            fail(&format!("cannot write to stdout: {}", error_message(e)));
+        }
+    }
+
+    if let Some(path) = matches.value_of("output") {
+        if let Err(e) = fs::write(path, &rendered) {
+            fail(&format!("cannot write to '{}': {}", path, error_message(e)));
+        }
+    }
+    for path in matches.values_of("tee").into_iter().flatten() {
+        if let Err(e) = fs::write(path, &rendered) {
+            fail(&format!("cannot write to '{}': {}", path, error_message(e)));
+        }
+    }
+
+    if matches.is_present("clipboard") {
+        match copy_to_clipboard(&rendered) {
+            Err(e) if !quiet => eprintln!("pic2term: unable to copy to the clipboard: {}", e),
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    // Red, green, blue and white as 4 to 7, on a 2x2 canvas. The encoder picks too small a code
+    // size for frames only using the first couple of colors, so those are left as black padding.
+    fn gif_data(frames: &[gif::Frame]) -> Vec<u8> {
+        let mut data = Vec::new();
+        {
+            let mut palette = vec![0x00; 12];
+            palette.extend_from_slice(&[0xff, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff]);
+            let mut encoder = gif::Encoder::new(&mut data, 2, 2, &palette).unwrap();
+            for frame in frames {
+                encoder.write_frame(frame).unwrap();
+            }
+        }
+        data
+    }
+
+    fn gif_frame(left: u16, top: u16, indices: &'static [u8], width: u16, delay: u16, dispose: DisposalMethod) -> gif::Frame<'static> {
+        let height = indices.len() as u16 / width;
+        gif::Frame { left, top, width, height, delay, dispose, buffer: Cow::Borrowed(indices), ..gif::Frame::default() }
+    }
+
+    #[test]
+    fn parse_palette_skips_comments_and_blank_lines() {
+        let text = "# Solarized, more or less\n\n002b36  # base03\n  #dc322f\n#\n\n859900\n";
+        assert_eq!(parse_palette(text).unwrap(), [[0x00, 0x2b, 0x36], [0xdc, 0x32, 0x2f], [0x85, 0x99, 0x00]]);
+    }
+
+    #[test]
+    fn parse_palette_rejects_bad_colors_and_empty_palettes() {
+        // Lines are counted as they are in the file, blank ones and comments included
+        assert_eq!(parse_palette("# colors\n000000\n\nff00zz\n").unwrap_err(), "line 4: 'ff00zz' is not a color of the form RRGGBB");
+        assert_eq!(parse_palette("fff").unwrap_err(), "line 1: 'fff' is not a color of the form RRGGBB");
+        assert_eq!(parse_palette("000000 ffffff").unwrap_err(), "line 1: '000000 ffffff' is not a color of the form RRGGBB");
+        assert_eq!(parse_palette("").unwrap_err(), "there are no colors in it");
+        assert_eq!(parse_palette("\n  \n# nothing but comments\n").unwrap_err(), "there are no colors in it");
+        assert_eq!(parse_palette(&"000000\n".repeat(257)).unwrap_err(), "it has 257 colors, but terminals only have 256");
+    }
+
+    #[test]
+    fn parse_osc_color_scales_every_number_of_digits() {
+        // Each channel goes up to as many hex digits as it has, whichever way the reply ends
+        assert_eq!(parse_osc_color("\x1b]11;rgb:f/8/0\x07"), Some([0xff, 0x88, 0x00]));
+        assert_eq!(parse_osc_color("\x1b]11;rgb:ff/80/00\x1b\\"), Some([0xff, 0x80, 0x00]));
+        assert_eq!(parse_osc_color("\x1b]11;rgb:fff/800/000\x07"), Some([0xff, 0x80, 0x00]));
+        assert_eq!(parse_osc_color("\x1b]11;rgb:ffff/8080/0000\x1b\\"), Some([0xff, 0x80, 0x00]));
+        assert_eq!(parse_osc_color("\x1b]11;rgb:1e1e/2/3c3\x07"), Some([0x1e, 0x22, 0x3c]));
+        // Whatever got read before the reply ended
+        assert_eq!(parse_osc_color("\x1b]11;rgb:0000/0000/0000"), Some([0x00, 0x00, 0x00]));
+    }
+
+    #[test]
+    fn parse_osc_color_rejects_anything_else() {
+        assert_eq!(parse_osc_color("\x1b]11;rgb:ffff/ffff\x07"), None);
+        assert_eq!(parse_osc_color("\x1b]11;rgb:ffff/ffff/ffff/ffff\x07"), None);
+        assert_eq!(parse_osc_color("\x1b]11;rgb:fffff/0/0\x07"), None);
+        assert_eq!(parse_osc_color("\x1b]11;rgb:/0/0\x07"), None);
+        assert_eq!(parse_osc_color("\x1b]11;rgb:+f/0/0\x07"), None);
+        assert_eq!(parse_osc_color("\x1b]11;rgb:g/0/0\x07"), None);
+        assert_eq!(parse_osc_color("\x1b]11;#ffffff\x07"), None);
+        assert_eq!(parse_osc_color(""), None);
+    }
+
+    // A config file of its own for each test, since they run at the same time
+    fn config_file(name: &str, text: &str) -> String {
+        let path = env::temp_dir().join(format!("pic2term-{}-{}.toml", process::id(), name));
+        fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn load_config_reads_every_setting() {
+        let path = config_file("every", "filter = \"gaussian\"\ndither = \"atkinson\"\ncolors = 16\ncell-aspect = 2.5\n");
+        let config = load_config(Some(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+        let settings = ["filter", "dither", "colors", "cell-aspect", "width"].iter().map(|name| config.get(name)).collect::<Vec<_>>();
+        assert_eq!(settings, [Some("gaussian".into()), Some("atkinson".into()), Some("16".into()), Some("2.5".into()), None]);
+    }
+
+    #[test]
+    fn load_config_rejects_bad_configs() {
+        let error = |name: &str, text: &str| {
+            let path = config_file(name, text);
+            let error = load_config(Some(&path)).err().unwrap();
+            fs::remove_file(&path).unwrap();
+            error
+        };
+        assert!(error("filter", "filter = \"blurry\"").contains("'blurry' isn't a valid filter, pick one of"));
+        assert!(error("dither", "dither = \"lots\"").contains("'lots' isn't a valid dither"));
+        assert!(error("colors", "colors = 100").contains("terminals have 256, 88 or 16 colors, not 100"));
+        assert!(error("unknown", "width = 80").contains("cannot load config"));
+        assert!(error("toml", "filter = ").contains("cannot load config"));
+        // Only a file asked for by name has to be there
+        let missing = env::temp_dir().join("pic2term-missing.toml").to_string_lossy().into_owned();
+        assert!(load_config(Some(&missing)).err().unwrap().starts_with("cannot open config"));
+    }
+
+    #[test]
+    fn settings_come_from_flags_then_the_config_then_the_defaults() {
+        let config = Config { filter: Some("gaussian".into()), dither: Some("atkinson".into()), ..Config::default() };
+        let settings = |args: &[&str], config: &Config| {
+            let matches = app().get_matches_from([&["pic2term"], args].concat());
+            ["filter", "dither", "colors"].iter().map(|name| setting(&matches, config, name)).collect::<Vec<_>>()
+        };
+        let defaults = settings(&[], &Config::default());
+        assert_eq!(defaults[..2], [Some("nearest".into()), Some("jarvis".into())]);
+        assert_eq!(defaults[2], None);
+        assert_eq!(settings(&[], &config), [Some("gaussian".into()), Some("atkinson".into()), None]);
+        // Even a flag given the same value as its default wins over the config file
+        assert_eq!(settings(&["--filter", "nearest", "--colors", "16"], &config), [Some("nearest".into()), Some("atkinson".into()), Some("16".into())]);
+    }
+
+    #[test]
+    fn parse_kernel_splits_the_error_by_weight() {
+        assert_eq!(parse_kernel("1,0,7; -1,1,3; 0,1,5; 1,1,1").unwrap(), [(1, 0, 7, 16), (-1, 1, 3, 16), (0, 1, 5, 16), (1, 1, 1, 16)]);
+        assert_eq!(parse_kernel("2,0,1").unwrap(), [(2, 0, 1, 1)]);
+    }
+
+    #[test]
+    fn parse_kernel_rejects_malformed_kernels() {
+        let error = |s: &str| parse_kernel(s).unwrap_err();
+        // Entries that aren't numbers, or don't have exactly three of them
+        assert!(error("1,0,x").contains("'1,0,x' is not of the form dx,dy,weight"));
+        assert!(error("").contains("is not of the form dx,dy,weight"));
+        assert!(error("1,0,7;0,1").contains("'0,1' is not of the form dx,dy,weight"));
+        assert!(error("1,0,7;0,1,5,1").contains("'0,1,5,1' is not of the form dx,dy,weight"));
+        // The current pixel, and anything already visited, can't take any error
+        assert!(error("0,0,1").contains("'0,0,1' is not ahead of the current pixel"));
+        assert!(error("1,0,7;-1,0,1").contains("'-1,0,1' is not ahead"));
+        assert!(error("1,-1,1").contains("is not ahead"));
+        // Weights that add up to nothing would divide by zero
+        assert!(error("1,0,0;0,1,0").contains("'1,0,0' does not have a positive weight"));
+        assert!(error("1,0,2;0,1,-2").contains("'0,1,-2' does not have a positive weight"));
+    }
+
+    fn solid_png(size: u32, color: [u8; 3]) -> Vec<u8> {
+        let mut data = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut data, size, size);
+            png::HasParameters::set(&mut encoder, png::ColorType::RGB);
+            encoder.write_header().unwrap().write_image_data(&color.repeat(size as usize * size as usize)).unwrap();
+        }
+        data
+    }
+
+    // An ICO of square PNGs, as sizes and colors
+    fn ico_data(images: &[(u32, [u8; 3])]) -> Vec<u8> {
+        let pngs = images.iter().map(|&(size, color)| solid_png(size, color)).collect::<Vec<_>>();
+        let mut data = vec![0, 0, 1, 0, images.len() as u8, 0];
+        let mut offset = 6 + 16 * images.len() as u32;
+        for (&(size, _), png) in images.iter().zip(&pngs) {
+            data.extend_from_slice(&[size as u8, size as u8, 0, 0, 1, 0, 32, 0]);
+            data.extend_from_slice(&(png.len() as u32).to_le_bytes());
+            data.extend_from_slice(&offset.to_le_bytes());
+            offset += png.len() as u32;
+        }
+        data.extend(pngs.concat());
+        data
+    }
+
+    // A little-endian TIFF of uncompressed grayscale pages, as sizes and levels
+    fn tiff_data(pages: &[(u32, u8)]) -> Vec<u8> {
+        let strips = pages.iter().map(|&(size, level)| vec![level; size as usize * size as usize]).collect::<Vec<_>>();
+        let mut data = b"II*\0".to_vec();
+        let mut directory = 8 + strips.iter().map(Vec::len).sum::<usize>() as u32;
+        data.extend_from_slice(&directory.to_le_bytes());
+        let mut strip = 8;
+        let mut directories = Vec::new();
+        for (i, (&(size, _), pixels)) in pages.iter().zip(&strips).enumerate() {
+            let tags = [(256, size), (257, size), (258, 8), (259, 1), (262, 1), (273, strip), (277, 1), (278, size), (279, pixels.len() as u32)];
+            directories.extend_from_slice(&(tags.len() as u16).to_le_bytes());
+            for &(tag, value) in &tags {
+                directories.extend_from_slice(&(tag as u16).to_le_bytes());
+                directories.extend_from_slice(&[4, 0, 1, 0, 0, 0]);
+                directories.extend_from_slice(&value.to_le_bytes());
+            }
+            directory += 2 + 12 * tags.len() as u32 + 4;
+            directories.extend_from_slice(&if i + 1 < pages.len() { directory } else { 0 }.to_le_bytes());
+            strip += pixels.len() as u32;
+        }
+        data.extend(strips.concat());
+        data.extend(directories);
+        data
+    }
+
+    fn frame_of(data: &[u8], select: FrameSelect) -> Result<(u32, u32, [u8; 3]), String> {
+        open_frame(data, "multi", select).map(|img| {
+            let img = img.to_rgb();
+            (img.width(), img.height(), img.get_pixel(0, 0).data)
+        })
+    }
+
+    #[test]
+    fn open_frame_picks_the_image_asked_for() {
+        let (red, green, blue) = ([0xff, 0, 0], [0, 0xff, 0], [0, 0, 0xff]);
+        let ico = ico_data(&[(16, red), (32, blue), (8, green)]);
+        assert_eq!(frame_of(&ico, FrameSelect::Largest), Ok((32, 32, blue)));
+        assert_eq!(frame_of(&ico, FrameSelect::Smallest), Ok((8, 8, green)));
+        assert_eq!(frame_of(&ico, FrameSelect::Index(0)), Ok((16, 16, red)));
+        let tiff = tiff_data(&[(4, 0x20), (8, 0x80)]);
+        assert_eq!(frame_of(&tiff, FrameSelect::Index(1)), Ok((8, 8, [0x80; 3])));
+        assert_eq!(frame_of(&tiff, FrameSelect::Largest), Ok((8, 8, [0x80; 3])));
+        assert_eq!(frame_of(&tiff, FrameSelect::Smallest), Ok((4, 4, [0x20; 3])));
+    }
+
+    #[test]
+    fn open_frame_refuses_frames_past_the_last() {
+        let ico = ico_data(&[(16, [0; 3]), (32, [0; 3])]);
+        assert_eq!(frame_of(&ico, FrameSelect::Index(2)), Err("frame 2 is out of range, 'multi' only has 2 images".into()));
+        let tiff = tiff_data(&[(4, 0), (8, 0), (2, 0)]);
+        assert_eq!(frame_of(&tiff, FrameSelect::Index(3)), Err("frame 3 is out of range, 'multi' only has 3 images".into()));
+        let png = solid_png(2, [0; 3]);
+        assert_eq!(frame_of(&png, FrameSelect::Index(1)), Err("frame 1 is out of range, 'multi' only has 1 image".into()));
+        assert_eq!(frame_of(&png, FrameSelect::Index(0)), Ok((2, 2, [0; 3])));
+    }
+
+    #[test]
+    fn open_data_decodes_the_data_it_was_given() {
+        // There's no such file to read it back from, only the name to go by
+        let png = solid_png(2, [0x80, 0x40, 0x20]);
+        assert_eq!(open_data(&png, "missing/image.png").unwrap().to_rgb().get_pixel(1, 1).data, [0x80, 0x40, 0x20]);
+        assert_eq!(open_data(&png, "missing/image").unwrap().to_rgb().get_pixel(1, 1).data, [0x80, 0x40, 0x20]);
+        assert_eq!(open_data(&png, "missing/image.PNG").unwrap().to_rgb().get_pixel(1, 1).data, [0x80, 0x40, 0x20]);
+        // A known extension still decides the format
+        assert!(open_data(&png, "missing/image.bmp").err().unwrap().starts_with("cannot decode 'missing/image.bmp'"));
+    }
+
+    #[test]
+    fn gif_frames_skip_what_a_truncated_file_cuts_off() {
+        let frames = [0, 1, 2].iter()
+            .map(|&idx| gif_frame(0, 0, [&[4, 4, 4, 4], &[5, 5, 5, 5], &[6, 6, 6, 6]][idx], 2, 5, DisposalMethod::Keep))
+            .collect::<Vec<_>>();
+        let data = gif_data(&frames);
+        assert_eq!(gif_frames(&data, "full.gif").unwrap().unwrap().len(), 3);
+
+        // Cutting into the last frame leaves the ones before it
+        let decoded = gif_frames(&data[..data.len() - 4], "cut.gif").unwrap().unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[1].0.get_pixel(1, 1).data, [0x00, 0xff, 0x00, 0xff]);
+
+        // With nothing left to show it's an error
+        let first_frame = data.iter().position(|&b| b == 0x2c).unwrap();
+        assert!(gif_frames(&data[..first_frame + 12], "cut.gif").err().unwrap().contains("cut.gif"));
+    }
+
+    #[test]
+    fn gif_frames_compose_dispose_and_time_each_frame() {
+        let mut last = gif_frame(0, 1, &[7, 0], 2, 20, DisposalMethod::Keep);
+        last.transparent = Some(0);
+        let data = gif_data(&[
+            gif_frame(0, 0, &[4, 4, 4, 4], 2, 0, DisposalMethod::Keep),
+            gif_frame(1, 1, &[5], 1, 5, DisposalMethod::Background),
+            gif_frame(0, 0, &[6], 1, 20, DisposalMethod::Previous),
+            last,
+        ]);
+        let frames = gif_frames(&data, "anim.gif").unwrap().unwrap();
+        let pixels = |idx: usize| frames[idx].0.pixels().map(|pixel| pixel.data).collect::<Vec<_>>();
+        let (red, green, blue, white, clear) = ([0xff, 0, 0, 0xff], [0, 0xff, 0, 0xff], [0, 0, 0xff, 0xff], [0xff; 4], [0; 4]);
+        assert_eq!(pixels(0), [red, red, red, red]);
+        assert_eq!(pixels(1), [red, red, red, green]);
+        // The green pixel was cleared to the background, and the blue one goes back to what it covered
+        assert_eq!(pixels(2), [blue, red, red, clear]);
+        assert_eq!(pixels(3), [red, red, white, clear]);
+        let delays = frames.iter().map(|&(_, delay)| delay.as_millis()).collect::<Vec<_>>();
+        assert_eq!(delays, [100, 50, 200, 200]);
+    }
+
+    // A row of 16 bit samples, with an sBIT chunk if it's given one
+    fn deep_png(color: png::ColorType, samples: &[u16], significant: Option<&[u8]>) -> Vec<u8> {
+        let mut data = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut data, (samples.len() / color.samples()) as u32, 1);
+            png::HasParameters::set(&mut encoder, color);
+            png::HasParameters::set(&mut encoder, png::BitDepth::Sixteen);
+            let mut writer = encoder.write_header().unwrap();
+            if let Some(significant) = significant {
+                writer.write_chunk(*b"sBIT", significant).unwrap();
+            }
+            writer.write_image_data(&samples.iter().flat_map(|v| v.to_be_bytes()).collect::<Vec<_>>()).unwrap();
+        }
+        data
+    }
+
+    fn open_deep(color: png::ColorType, samples: &[u16], significant: Option<&[u8]>, stretch: bool) -> Vec<u8> {
+        open_deep_png(&deep_png(color, samples, significant), stretch).unwrap().unwrap().raw_pixels()
+    }
+
+    #[test]
+    fn open_deep_png_rounds_16_bits_down_to_8() {
+        assert_eq!(open_deep(png::ColorType::Grayscale, &[0x0000, 0x8080, 0xffff], None, false), [0x00, 0x80, 0xff]);
+        assert_eq!(open_deep(png::ColorType::RGB, &[0xffff, 0x0000, 0x4000], None, false), [0xff, 0x00, 0x40]);
+        // Anything else is left to image
+        let mut data = Vec::new();
+        png::Encoder::new(&mut data, 1, 1).write_header().unwrap().write_image_data(&[0x80, 0x80, 0x80, 0xff]).unwrap();
+        assert!(open_deep_png(&data, false).is_none());
+        assert!(open_deep_png(b"GIF89a", false).is_none());
+    }
+
+    #[test]
+    fn open_deep_png_scales_by_the_significant_bits() {
+        // 12 bits, shifted up to the top as they should be, and left in the low bits as they often are
+        let gray = Some(&[12][..]);
+        assert_eq!(open_deep(png::ColorType::Grayscale, &[0, 2048 << 4, 4095 << 4], gray, false), [0x00, 0x80, 0xff]);
+        assert_eq!(open_deep(png::ColorType::Grayscale, &[0, 2049, 4095], gray, false), [0x00, 0x80, 0xff]);
+        // Every channel can have a depth of its own
+        let rgb = Some(&[5, 6, 5][..]);
+        assert_eq!(open_deep(png::ColorType::RGB, &[31 << 11, 63 << 10, 16 << 11], rgb, false), [0xff, 0xff, 0x84]);
+        assert_eq!(open_deep(png::ColorType::RGB, &[31, 63, 16], rgb, false), [0xff, 0xff, 0x84]);
+    }
+
+    #[test]
+    fn open_deep_png_only_stretches_the_levels_when_asked() {
+        // Samples spanning 0..3840 with nothing saying so come out dark, unless they're stretched out
+        let samples = [0, 1920, 3840];
+        assert_eq!(open_deep(png::ColorType::Grayscale, &samples, None, false), [0x00, 0x07, 0x0f]);
+        assert_eq!(open_deep(png::ColorType::Grayscale, &samples, None, true), [0x00, 0x80, 0xff]);
+        assert_eq!(open_deep(png::ColorType::RGB, &samples, None, true), [0x00, 0x80, 0xff]);
+    }
+
+    // A 16x16 lossy frame as mid gray, which is everything predicted from nothing and then left alone
+    static GRAY_VP8: [u8; 20] = [
+        0x10, 0x01, 0x00, 0x9d, 0x01, 0x2a, 0x10, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x65, 0xa0, 0x00, 0x00,
+    ];
+
+    // An animated WebP on a 32x16 canvas, of gray frames placed, timed and disposed of as given
+    fn webp_data(frames: &[(u32, u32, bool)]) -> Vec<u8> {
+        let chunk = |kind: &[u8], body: &[u8]| [kind, &(body.len() as u32).to_le_bytes(), body].concat();
+        let u24 = |n: u32| n.to_le_bytes()[..3].to_vec();
+        let mut body = b"WEBP".to_vec();
+        body.extend(chunk(b"VP8X", &[vec![0x02, 0, 0, 0], u24(31), u24(15)].concat()));
+        body.extend(chunk(b"ANIM", &[0; 6]));
+        for &(x, delay, dispose) in frames {
+            let header = [u24(x / 2), u24(0), u24(15), u24(15), u24(delay), vec![dispose as u8]].concat();
+            body.extend(chunk(b"ANMF", &[header, chunk(b"VP8 ", &GRAY_VP8)].concat()));
+        }
+        chunk(b"RIFF", &body)
+    }
+
+    #[test]
+    fn webp_frames_compose_dispose_and_time_each_frame() {
+        let frames = webp_frames(&webp_data(&[(0, 50, true), (16, 10, false), (0, 200, false)]), "anim.webp").unwrap().unwrap();
+        // Which halves of the canvas are showing anything
+        let halves = |idx: usize| {
+            let frame: &RgbaImage = &frames[idx].0;
+            (frame.get_pixel(8, 8).data[3], frame.get_pixel(24, 8).data[3])
+        };
+        assert_eq!(halves(0), (0xff, 0x00));
+        // The first frame was disposed of, leaving only the second
+        assert_eq!(halves(1), (0x00, 0xff));
+        assert_eq!(halves(2), (0xff, 0xff));
+        assert!(frames[2].0.get_pixel(8, 8).data[..3].iter().all(|&n| n.abs_diff(0x80) <= 2));
+        let delays = frames.iter().map(|&(_, delay)| delay.as_millis()).collect::<Vec<_>>();
+        assert_eq!(delays, [50, 100, 200]);
+        // A still WebP isn't an animation at all
+        let still = [&b"RIFF"[..], &32u32.to_le_bytes(), b"WEBPVP8 ", &20u32.to_le_bytes(), &GRAY_VP8].concat();
+        assert!(webp_frames(&still, "still.webp").unwrap().is_none());
+    }
+}